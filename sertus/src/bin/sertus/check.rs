@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use sertus::{
+    app_error,
+    checker::{script::ScriptChecker, Checker},
+    config,
+    error::Result,
+    executor::Executor,
+    metric_ext::invalid_directives,
+};
+use tokio::time::timeout;
+
+/// run the checker of a `flow/task`, or a script, and print what the flow would extract
+/// from its output, returns the status of the check
+pub async fn check(target: Option<String>, script: Option<String>) -> Result<bool> {
+    let (checker, secs) = match (target, script) {
+        (_, Some(path)) => (Checker::ScriptChecker(ScriptChecker::new(path)), None),
+        (Some(target), None) => {
+            let config = config::read()?;
+            config
+                .flows
+                .iter()
+                .flat_map(|flow| flow.tasks.iter().map(move |task| (flow, task)))
+                .find(|(flow, task)| format!("{}/{}", flow.name, task.name) == target)
+                .map(|(flow, task)| (task.checker.clone(), task.timeout.or(flow.timeout)))
+                .ok_or_else(|| app_error!("Task {} not found, expected <flow>/<task>", target))?
+        }
+        (None, None) => return Err(app_error!("Either <flow>/<task> or --script is required")),
+    };
+    println!("checker: {}", checker);
+    let result = match secs {
+        Some(secs) => timeout(Duration::from_secs(secs), checker.exec())
+            .await
+            .map_err(|_| app_error!("Timeout after {}s", secs))??,
+        None => checker.exec().await?,
+    };
+    println!(
+        "exit code: {}",
+        result
+            .exit_code
+            .map_or("none".to_string(), |code| code.to_string())
+    );
+    println!("duration: {:?}", result.duration);
+    print_section("stdout", &result.stdout);
+    print_section("stderr", &result.stderr);
+    println!("status: {:?}", result.status);
+    println!("message: {}", result.message);
+
+    println!("labels:");
+    for (key, value) in result.labels.iter() {
+        println!("  {} = {}", key, value);
+    }
+    println!("metrics:");
+    for metric in result.metrics.iter() {
+        println!("  {:?}", metric);
+    }
+    if !result.families.is_empty() {
+        println!("exposition:");
+        for family in result.families.iter() {
+            println!(
+                "  {} {} ({} samples)",
+                family.name,
+                family.typ.as_str(),
+                family.samples.len()
+            );
+        }
+    }
+    println!("invalid directives:");
+    for (name, output) in [("stdout", &result.stdout), ("stderr", &result.stderr)] {
+        for invalid in invalid_directives(output)? {
+            println!(
+                "  {} line {}: {}\n    {}",
+                name, invalid.line_number, invalid.line, invalid.reason
+            );
+        }
+    }
+    Ok(result.is_ok())
+}
+
+fn print_section(name: &str, content: &str) {
+    println!("--- {} ---", name);
+    if !content.is_empty() {
+        println!("{}", content.trim_end_matches('\n'));
+    }
+}
@@ -1,26 +1,46 @@
+use std::fs;
+
 use dialoguer::Editor;
 use sconfig::Configurable;
-use sertus::config::{with_config, Config};
+use sertus::config::{self, Config};
 use tracing::error;
 
+/// edit config.toml, flow files in flows.d are edited on their own
 pub async fn editor() {
-    with_config(|c| async move {
-        if let Some(rv) = Editor::new().edit(c.to_string().as_str()).unwrap() {
-            let new_config = rv
-                .parse::<Config>()
-                .inspect_err(|e| error!("config parse err: {}", e))
-                .ok();
-            if let Some(new_config) = new_config {
-                if let Err(e) = new_config.write() {
-                    error!("config save err: {}", e);
-                } else {
-                    println!("Config has been updated:");
-                    println!("{}\n", rv);
-                }
+    let config_path = Config::default().config_path();
+    let content = match fs::read_to_string(&config_path) {
+        Ok(content) => content,
+        Err(e) => {
+            error!("Read file {}: {}", config_path.display(), e);
+            return;
+        }
+    };
+    if let Some(rv) = Editor::new().edit(content.as_str()).unwrap() {
+        let new_config = rv
+            .parse::<Config>()
+            .inspect_err(|e| error!("config parse err: {}", e))
+            .ok();
+        if new_config.is_some() {
+            if let Err(e) = fs::write(&config_path, &rv) {
+                error!("config save err: {}", e);
+            } else {
+                println!("Config has been updated:");
+                println!("{}\n", rv);
             }
-        } else {
-            println!("Abort!");
         }
-    })
-    .await;
+    } else {
+        println!("Abort!");
+    }
+}
+
+/// print the problems of config and flow files, returns true when there is none
+pub fn validate() -> bool {
+    let diagnostics = config::diagnostics();
+    for diagnostic in diagnostics.iter() {
+        println!("{}", diagnostic);
+    }
+    if diagnostics.is_empty() {
+        println!("Config is valid");
+    }
+    diagnostics.is_empty()
 }
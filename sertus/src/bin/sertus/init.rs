@@ -1,13 +1,36 @@
+use std::{fs, os::unix::fs::PermissionsExt};
+
 use dialoguer::{console::Style, theme::ColorfulTheme, Confirm, Input, Select};
 use sconfig::Configurable;
 use sertus::{
-    checker::{process::ProcessChecker, script::ScriptChecker, Checker},
+    checker::{http::HttpChecker, process::ProcessChecker, script::ScriptChecker, Checker},
     config::Config,
     error::Result,
     flow::Flow,
     metrics::{Metrics, PushGateway, Server},
     task::Task,
 };
+
+/// path of the sample script, relative to the config dir
+const SAMPLE_SCRIPT: &str = "scripts/script.sh";
+
+/// write the sample script into the config dir unless it exists
+fn init_sample_script(config: &Config) -> Result<()> {
+    let path = config.config_dir().join(SAMPLE_SCRIPT);
+    if path.exists() {
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(
+        &path,
+        "#!/bin/bash\n# print the message, labels and metrics of the check\necho \"#label {name=sample}\"\necho \"#metric sample_value gauge {name=sample} 1\"\necho ok\n",
+    )?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
 pub fn default(force: bool) -> Result<()> {
     let mut config = Config::default();
     let mut flow1 = Flow::new("flow 1");
@@ -18,11 +41,12 @@ pub fn default(force: bool) -> Result<()> {
         ))
         .add_task(Task::new(
             "check script",
-            Checker::ScriptChecker(ScriptChecker::new("~/.sertus/scripts/script.sh")),
+            Checker::ScriptChecker(ScriptChecker::new(SAMPLE_SCRIPT)),
         ));
     config.add_flow(flow1);
 
     config.init(force)?;
+    init_sample_script(&config)?;
     Ok(())
 }
 
@@ -72,6 +96,7 @@ pub fn interact(mut force: bool) -> Result<()> {
         .default(0)
         .item("Process")
         .item("Script")
+        .item("Http")
         .interact()?;
     let checker = match checker_item {
         0 => Checker::ProcessChecker(ProcessChecker {
@@ -83,7 +108,7 @@ pub fn interact(mut force: bool) -> Result<()> {
         1 => Checker::ScriptChecker(ScriptChecker {
             path: Input::with_theme(&theme)
                 .with_prompt("path")
-                .default("~/.sertus/scripts/script.sh".to_string())
+                .default(SAMPLE_SCRIPT.to_string())
                 .interact()?,
             bin: Some(
                 Input::with_theme(&theme)
@@ -91,9 +116,19 @@ pub fn interact(mut force: bool) -> Result<()> {
                     .default("bash".to_string())
                     .interact()?,
             ),
+            args: None,
+            mode: None,
+            output: None,
         }),
+        2 => Checker::HttpChecker(HttpChecker::new(
+            Input::<String>::with_theme(&theme)
+                .with_prompt("url")
+                .default("http://127.0.0.1:8080/health".to_string())
+                .interact()?,
+        )),
         _ => unreachable!(),
     };
+    let sample = matches!(&checker, Checker::ScriptChecker(s) if s.path == SAMPLE_SCRIPT);
     flow1.add_task(Task::new(task_name, checker));
     config.add_flow(flow1);
     if !force {
@@ -102,5 +137,25 @@ pub fn interact(mut force: bool) -> Result<()> {
             .interact()?;
     }
     config.init(force)?;
+    if sample {
+        init_sample_script(&config)?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_validates() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // the config dir is read once, before any other config access
+        std::env::set_var("SERTUS_PATH", dir.path());
+        default(false)?;
+        assert!(dir.path().join(SAMPLE_SCRIPT).is_file());
+        let diagnostics = sertus::config::diagnostics();
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        Ok(())
+    }
+}
@@ -1,15 +1,18 @@
 #![feature(result_option_inspect)]
+use std::process::ExitCode;
+
 use clap::{Parser, Subcommand};
 use sertus::{
-    config::with_config,
+    daemon,
     error::Result,
-    metrics::{setup_pushgateway, start_metrics_server, Metrics},
     pkg::{log::init_tracing, version},
 };
-use tracing::{debug, info};
+use tracing::{error, info};
 
+pub mod check;
 pub mod config;
 pub mod init;
+pub mod run;
 
 /// Sertus program
 #[derive(Parser, Debug)]
@@ -30,8 +33,33 @@ enum Command {
         #[clap(short, long)]
         force: bool,
     },
-    /// Run daemon
-    Daemon,
+    /// Run daemon, send SIGHUP to reload config, SIGTERM or SIGINT to shut down
+    Daemon {
+        /// reload config when the config file changes
+        #[clap(short, long)]
+        watch: bool,
+    },
+    /// Run flows without the daemon, exits non-zero when any task did not succeed
+    Run {
+        /// run every task once and print the results
+        #[clap(long, required = true)]
+        once: bool,
+        /// only run the flow
+        #[clap(short, long)]
+        flow: Option<String>,
+        /// only run the task, without its dependencies
+        #[clap(short, long)]
+        task: Option<String>,
+    },
+    /// Run the checker of a task once and show the labels and metrics extracted from its output
+    Check {
+        /// <flow>/<task> in the config
+        #[clap(required_unless_present = "script")]
+        target: Option<String>,
+        /// a script to check instead of a task
+        #[clap(long, conflicts_with = "target")]
+        script: Option<String>,
+    },
     /// Config subcommands
     #[clap(subcommand)]
     Config(ConfigCommand),
@@ -41,43 +69,58 @@ enum Command {
 enum ConfigCommand {
     /// Edit config
     Edit,
+    /// Validate config and flow files, exits non-zero when any problem is found
+    Validate,
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
     init_tracing();
     let cli = Cli::parse();
+    match execute(cli).await {
+        Ok(code) => code,
+        Err(e) => {
+            // display the error instead of its debug form, e.g. the lines of config diagnostics
+            error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn execute(cli: Cli) -> Result<ExitCode> {
     match cli.commnad {
         Command::Init { interact, force } => match interact {
             true => init::interact(force)?,
             false => init::default(force)?,
         },
-        Command::Daemon => {
+        Command::Daemon { watch } => {
             info!("Initializing daemon");
-            with_config(|c| async move {
-                debug!("With config: {:#?}", c);
-                match c.metrics {
-                    Metrics::Server(s) => {
-                        tokio::spawn(start_metrics_server(s));
-                    }
-                    Metrics::PushGateway(p) => {
-                        tokio::spawn(setup_pushgateway(p));
-                    }
-                }
-
-                for flow in c.flows.into_iter() {
-                    tokio::spawn(flow.run());
-                }
-            })
-            .await;
-            std::future::pending::<()>().await;
+            if !daemon::run(watch).await? {
+                // running checkers were killed or the final metrics flush failed
+                return Ok(ExitCode::from(2));
+            }
+        }
+        Command::Run { flow, task, .. } => {
+            if !run::once(flow, task).await? {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Check { target, script } => {
+            if !check::check(target, script).await? {
+                return Ok(ExitCode::FAILURE);
+            }
         }
         Command::Config(config_command) => match config_command {
             ConfigCommand::Edit => {
                 config::editor().await;
             }
+            ConfigCommand::Validate => {
+                if !config::validate() {
+                    return Ok(ExitCode::FAILURE);
+                }
+            }
         },
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,65 @@
+use sertus::{app_error, config, error::Result, flow::set_max_concurrency, task::Outcome};
+
+/// run flows once, or only the selected flow and task, and print a result table,
+/// returns false when any task did not succeed
+pub async fn once(flow: Option<String>, task: Option<String>) -> Result<bool> {
+    let config = config::read()?;
+    set_max_concurrency(config.max_concurrency);
+    let flows = config
+        .flows
+        .into_iter()
+        .filter(|f| flow.as_ref().map_or(true, |name| &f.name == name))
+        .filter(|f| {
+            task.as_ref()
+                .map_or(true, |name| f.tasks.iter().any(|t| &t.name == name))
+        })
+        .collect::<Vec<_>>();
+    if flows.is_empty() {
+        return Err(match (flow, task) {
+            (Some(flow), Some(task)) => app_error!("Flow({}) has no Task({})", flow, task),
+            (Some(flow), None) => app_error!("Flow({}) not found", flow),
+            (None, Some(task)) => app_error!("Task({}) not found", task),
+            (None, None) => app_error!("No flows in the config"),
+        });
+    }
+
+    let mut results = vec![];
+    for flow in flows {
+        let name = flow.name.clone();
+        for (task, outcome) in flow.run_once(task.as_deref()).await? {
+            results.push((name.clone(), task, outcome));
+        }
+    }
+    print_table(&results);
+    Ok(results
+        .iter()
+        .all(|(_, _, outcome)| *outcome == Outcome::Success))
+}
+
+fn print_table(results: &[(String, String, Outcome)]) {
+    let rows = results
+        .iter()
+        .map(|(flow, task, outcome)| [flow.clone(), task.clone(), format!("{:?}", outcome)])
+        .collect::<Vec<_>>();
+    let header = [
+        "FLOW".to_string(),
+        "TASK".to_string(),
+        "OUTCOME".to_string(),
+    ];
+    let mut widths = [0; 3];
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        println!(
+            "{:<w0$}  {:<w1$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            w0 = widths[0],
+            w1 = widths[1]
+        );
+    }
+}
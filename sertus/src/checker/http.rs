@@ -0,0 +1,311 @@
+use std::{collections::BTreeMap, fmt::Display, str::FromStr, time::Duration};
+
+use async_trait::async_trait;
+use once_cell::sync::OnceCell;
+use regex::Regex;
+use reqwest::{Client, Method, Url};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use super::{CheckResult, Status};
+use crate::{app_error, error::Result, executor::Executor, metric_ext::MetricStruct};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HttpChecker {
+    pub url: String,
+    /// HTTP method, default GET
+    pub method: Option<String>,
+    pub headers: Option<BTreeMap<String, String>>,
+    pub body: Option<String>,
+    /// Expected status codes, default any 2xx
+    pub status: Option<Vec<u16>>,
+    /// Regex the response body must match
+    pub body_regex: Option<String>,
+    /// Assertions on a JSON response body
+    pub json: Option<Vec<JsonAssertion>>,
+    /// Request timeout, default 10(s)
+    pub timeout: Option<u64>,
+    /// built on the first request and reused, which keeps connections alive
+    #[serde(skip)]
+    client: OnceCell<Client>,
+}
+
+/// `path` is a dotted path like `$.data.items.0.status`,
+/// when `value` is none the path only has to exist.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JsonAssertion {
+    pub path: String,
+    pub value: Option<serde_json::Value>,
+}
+
+impl HttpChecker {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            method: None,
+            headers: None,
+            body: None,
+            status: None,
+            body_regex: None,
+            json: None,
+            timeout: None,
+            client: OnceCell::new(),
+        }
+    }
+
+    /// check the url and the body regex without a request
+    pub fn validate(&self) -> Result<()> {
+        Url::parse(&self.url).map_err(|e| app_error!("invalid url {:?}: {}", self.url, e))?;
+        if let Some(body_regex) = &self.body_regex {
+            Regex::new(body_regex)
+                .map_err(|e| app_error!("invalid body_regex {:?}: {}", body_regex, e))?;
+        }
+        Ok(())
+    }
+
+    fn client(&self) -> Result<&Client> {
+        self.client.get_or_try_init(|| {
+            Ok(Client::builder()
+                .timeout(Duration::from_secs(self.timeout.unwrap_or(10)))
+                .build()?)
+        })
+    }
+
+    fn status_expected(&self, code: u16) -> bool {
+        match &self.status {
+            Some(codes) => codes.contains(&code),
+            None => (200..300).contains(&code),
+        }
+    }
+
+    /// check the response body, return the reason of failure
+    fn check_body(&self, body: &str) -> Result<Option<String>> {
+        if let Some(body_regex) = &self.body_regex {
+            if !Regex::new(body_regex)?.is_match(body) {
+                return Ok(Some(format!("body does not match /{}/", body_regex)));
+            }
+        }
+        if let Some(assertions) = &self.json {
+            let json = match serde_json::from_str::<serde_json::Value>(body) {
+                Ok(json) => json,
+                Err(e) => return Ok(Some(format!("body is not json: {}", e))),
+            };
+            for assertion in assertions {
+                match (json_path(&json, &assertion.path), &assertion.value) {
+                    (None, _) => {
+                        return Ok(Some(format!("json path {} not found", assertion.path)))
+                    }
+                    (Some(found), Some(expected)) if found != expected => {
+                        return Ok(Some(format!(
+                            "json path {} expected {}, found {}",
+                            assertion.path, expected, found
+                        )))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// lookup a dotted path, array items are addressed by index
+fn json_path<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    let path = path.trim_start_matches('$').trim_start_matches('.');
+    if path.is_empty() {
+        return Some(value);
+    }
+    path.split('.').try_fold(value, |v, key| match v {
+        serde_json::Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+        _ => v.get(key),
+    })
+}
+
+impl Display for HttpChecker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            self.method.as_deref().unwrap_or("GET"),
+            self.url
+        )
+    }
+}
+
+#[async_trait]
+impl Executor for HttpChecker {
+    type Output = CheckResult;
+    async fn exec(&self) -> Result<Self::Output> {
+        let url =
+            Url::parse(&self.url).map_err(|e| app_error!("invalid url {}: {}", self.url, e))?;
+        let method = Method::from_str(
+            &self
+                .method
+                .clone()
+                .unwrap_or("GET".to_string())
+                .to_uppercase(),
+        )
+        .map_err(|e| app_error!("invalid method: {}", e))?;
+        let mut request = self.client()?.request(method.clone(), url.clone());
+        if let Some(headers) = &self.headers {
+            for (k, v) in headers {
+                request = request.header(k, v);
+            }
+        }
+        if let Some(body) = &self.body {
+            request = request.body(body.clone());
+        }
+
+        // the query may contain `,` or `=` which can not be a label value
+        let mut metric_url = url;
+        metric_url.set_query(None);
+        metric_url.set_fragment(None);
+        let metric_labels = vec![
+            ("method".to_string(), method.to_string()),
+            ("url".to_string(), metric_url.to_string()),
+        ];
+
+        let start = Instant::now();
+        let response = request.send().await;
+        let mut metrics = vec![MetricStruct::gauge(
+            "http_response_time_seconds",
+            metric_labels.clone(),
+            start.elapsed().as_secs_f64(),
+        )];
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                let status = match e.is_timeout() {
+                    true => Status::Timeout,
+                    false => Status::Critical,
+                };
+                let mut result = CheckResult::new(status, format!("request failed: {}", e));
+                result.metrics = metrics;
+                return Ok(result);
+            }
+        };
+        let code = response.status().as_u16();
+        metrics.push(MetricStruct::gauge(
+            "http_status_code",
+            metric_labels,
+            code as f64,
+        ));
+        let body = response.text().await.unwrap_or_default();
+        let mut result = if !self.status_expected(code) {
+            CheckResult::critical(format!("unexpected status code: {}", code))
+        } else if let Some(reason) = self.check_body(&body)? {
+            CheckResult::critical(reason)
+        } else {
+            CheckResult::ok(format!("status code: {}", code))
+        };
+        // a body may carry labels and metrics like the output of a script
+        result.extract_directives(&body)?;
+        result.metrics.extend(metrics);
+        result.stdout = body;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+
+    use axum::{http::StatusCode, routing::get, Router};
+    use serde_json::json;
+
+    use super::*;
+
+    fn serve() -> SocketAddr {
+        let app = Router::new()
+            .route(
+                "/health",
+                get(|| async { r#"{"status":"up","items":[{"id":1}]}"# }),
+            )
+            .route(
+                "/directives",
+                get(|| async {
+                    "ok\n#label {queue=jobs}\n#metric queue_size gauge {queue=jobs} 42\n"
+                }),
+            )
+            .route(
+                "/down",
+                get(|| async { (StatusCode::SERVICE_UNAVAILABLE, "down") }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_http_checker() {
+        let addr = serve();
+        let mut checker = HttpChecker::new(format!("http://{}/health", addr));
+        checker.body_regex = Some("up".to_string());
+        checker.json = Some(vec![
+            JsonAssertion {
+                path: "$.status".to_string(),
+                value: Some(json!("up")),
+            },
+            JsonAssertion {
+                path: "items.0.id".to_string(),
+                value: None,
+            },
+        ]);
+        let result = checker.exec().await.unwrap();
+        assert!(result.is_ok(), "{}", result.message);
+        assert_eq!(result.metrics.len(), 2);
+        assert!(result.labels.is_empty());
+        assert_eq!(result.metrics[1].name, "http_status_code");
+
+        checker.json = Some(vec![JsonAssertion {
+            path: "$.status".to_string(),
+            value: Some(json!("down")),
+        }]);
+        assert!(!checker.exec().await.unwrap().is_ok());
+        // the client of the first request is reused
+        assert!(checker.client.get().is_some());
+
+        checker.body_regex = Some("(up".to_string());
+        assert!(checker.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_checker_status() {
+        let addr = serve();
+        let mut checker = HttpChecker::new(format!("http://{}/down", addr));
+        assert!(!checker.exec().await.unwrap().is_ok());
+        checker.status = Some(vec![503]);
+        assert!(checker.exec().await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_http_checker_directives() {
+        let addr = serve();
+        let checker = HttpChecker::new(format!("http://{}/directives", addr));
+        let result = checker.exec().await.unwrap();
+        assert!(result.is_ok(), "{}", result.message);
+        assert_eq!(
+            result.labels,
+            vec![("queue".to_string(), "jobs".to_string())]
+        );
+        let names = result
+            .metrics
+            .iter()
+            .map(|m| m.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "queue_size",
+                "http_response_time_seconds",
+                "http_status_code"
+            ]
+        );
+        assert_eq!(f64::from(result.metrics[0].value.clone()), 42.0);
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use super::{CheckResult, Status};
+use crate::{
+    app_error,
+    error::Result,
+    metric_ext::{MetricStruct, MetricValue},
+};
+
+/// The JSON document printed by a script with `output = "json"`
+/// ```json
+/// {"status": "ok", "message": "all good", "labels": {"k": "v"},
+///  "metrics": [{"name": "queue_size", "type": "gauge", "labels": {"queue": "mail"}, "value": 3}]}
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Output {
+    status: Status,
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(default)]
+    metrics: Vec<Metric>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Metric {
+    name: String,
+    #[serde(rename = "type")]
+    typ: MetricType,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    value: Value,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum MetricType {
+    Gauge,
+    Counter,
+    Histogram,
+    Summary,
+}
+
+/// a number, or a list of observations of a histogram or summary
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Value {
+    Number(serde_json::Number),
+    Observations(Vec<f64>),
+}
+
+impl Metric {
+    fn into_metric(self, index: usize) -> Result<MetricStruct> {
+        let error = |reason: String| app_error!("metrics[{}] {:?}: {}", index, self.name, reason);
+        let valid_name = self.name.chars().enumerate().all(|(i, c)| {
+            c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit())
+        });
+        if self.name.is_empty() || !valid_name {
+            return Err(error("invalid metric name".to_string()));
+        }
+        let value = match (self.typ, &self.value) {
+            (MetricType::Gauge, Value::Number(n)) => n.as_f64().map(MetricValue::F64),
+            (MetricType::Counter, Value::Number(n)) => n.as_u64().map(MetricValue::U64),
+            (MetricType::Histogram | MetricType::Summary, Value::Number(n)) => {
+                n.as_f64().map(|v| MetricValue::Observations(vec![v]))
+            }
+            (MetricType::Histogram | MetricType::Summary, Value::Observations(v)) => {
+                Some(MetricValue::Observations(v.clone()))
+            }
+            (_, Value::Observations(_)) => {
+                return Err(error(format!(
+                    "a list of values is only allowed for histograms and summaries, not {:?}",
+                    self.typ
+                )))
+            }
+        };
+        let value = value.ok_or_else(|| match self.typ {
+            MetricType::Counter => error("counter value is not an unsigned integer".to_string()),
+            _ => error("value is not a number".to_string()),
+        })?;
+        let typ = match self.typ {
+            MetricType::Gauge => "gauge",
+            MetricType::Counter => "counter",
+            MetricType::Histogram => "histogram",
+            MetricType::Summary => "summary",
+        };
+        Ok(MetricStruct {
+            name: self.name,
+            typ: typ.to_string(),
+            labels: self.labels.into_iter().collect(),
+            value,
+        })
+    }
+}
+
+/// parse the JSON document of a script strictly, unknown fields and wrong types are errors
+pub fn parse(stdout: &str) -> Result<CheckResult> {
+    let output: Output = serde_json::from_str(stdout).map_err(|e| app_error!("{}", e))?;
+    let mut result = CheckResult::new(output.status, output.message);
+    result.labels = output.labels.into_iter().collect();
+    result.metrics = output
+        .metrics
+        .into_iter()
+        .enumerate()
+        .map(|(index, metric)| metric.into_metric(index))
+        .collect::<Result<_>>()?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let result = parse(
+            r#"{"status": "warning", "message": "queue is growing", "labels": {"host": "a"},
+                "metrics": [
+                    {"name": "queue_size", "type": "gauge", "labels": {"queue": "mail, sms"}, "value": 3.5},
+                    {"name": "jobs", "type": "counter", "value": 7},
+                    {"name": "latency_seconds", "type": "histogram", "value": [0.1, 0.2]}
+                ]}"#,
+        )?;
+        assert_eq!(result.status, Status::Warning);
+        assert_eq!(result.message, "queue is growing");
+        assert_eq!(result.labels, vec![("host".to_string(), "a".to_string())]);
+        assert_eq!(
+            result.metrics[0].labels,
+            vec![("queue".to_string(), "mail, sms".to_string())]
+        );
+        assert_eq!(result.metrics[1].value, MetricValue::U64(7));
+        assert_eq!(
+            result.metrics[2].value,
+            MetricValue::Observations(vec![0.1, 0.2])
+        );
+        assert!(parse(r#"{"status": "ok"}"#)?.is_ok());
+
+        for invalid in [
+            "all good",
+            r#"{"message": "no status"}"#,
+            r#"{"status": "fine"}"#,
+            r#"{"status": "ok", "extra": 1}"#,
+            r#"{"status": "ok", "labels": {"k": 1}}"#,
+            r#"{"status": "ok", "metrics": [{"name": "x", "type": "meter", "value": 1}]}"#,
+            r#"{"status": "ok", "metrics": [{"name": "x", "type": "counter", "value": -1}]}"#,
+            r#"{"status": "ok", "metrics": [{"name": "x", "type": "gauge", "value": [1]}]}"#,
+            r#"{"status": "ok", "metrics": [{"name": "x-y", "type": "gauge", "value": 1}]}"#,
+        ] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+        let err = parse(
+            r#"{"status": "ok", "metrics": [{"name": "x", "type": "counter", "value": 1.5}]}"#,
+        )
+        .unwrap_err()
+        .to_string();
+        assert_eq!(
+            err,
+            r#"metrics[0] "x": counter value is not an unsigned integer"#
+        );
+        Ok(())
+    }
+}
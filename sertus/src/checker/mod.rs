@@ -1,27 +1,65 @@
-use std::fmt::Display;
+use std::{fmt::Display, path::Path, time::Instant};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{error::Result, executor::Executor};
 
-use self::{process::ProcessChecker, script::ScriptChecker};
+pub use self::result::{CheckResult, Status};
+use self::{
+    http::HttpChecker, process::ProcessChecker, script::ScriptChecker, tcp::TcpChecker,
+    tls::TlsChecker,
+};
 
+pub mod http;
+pub mod json;
+pub mod nagios;
 pub mod process;
+pub mod result;
 pub mod script;
+pub mod tcp;
+pub mod tls;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Checker {
     ProcessChecker(ProcessChecker),
     ScriptChecker(ScriptChecker),
+    HttpChecker(HttpChecker),
+    TcpChecker(TcpChecker),
+    TlsChecker(TlsChecker),
 }
+impl Checker {
+    /// check the config of the checker without running it
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Checker::ScriptChecker(checker) => checker.validate(),
+            Checker::HttpChecker(checker) => checker.validate(),
+            Checker::TcpChecker(checker) => checker.validate(),
+            _ => Ok(()),
+        }
+    }
+
+    /// resolve relative paths of the checker against `dir`, e.g. the config dir
+    pub fn resolve(&mut self, dir: &Path) {
+        if let Checker::ScriptChecker(checker) = self {
+            checker.resolve(dir);
+        }
+    }
+}
+
 #[async_trait::async_trait]
 impl Executor for Checker {
-    type Output = (bool, String);
+    type Output = CheckResult;
     async fn exec(&self) -> Result<Self::Output> {
-        match self {
+        let start = Instant::now();
+        let mut result = match self {
             Checker::ProcessChecker(checker) => checker.exec().await,
             Checker::ScriptChecker(checker) => checker.exec().await,
-        }
+            Checker::HttpChecker(checker) => checker.exec().await,
+            Checker::TcpChecker(checker) => checker.exec().await,
+            Checker::TlsChecker(checker) => checker.exec().await,
+        }?;
+        result.duration = start.elapsed();
+        Ok(result)
     }
 }
 
@@ -34,6 +72,15 @@ impl Display for Checker {
             Checker::ScriptChecker(p) => {
                 write!(f, "{}", p)
             }
+            Checker::HttpChecker(p) => {
+                write!(f, "{}", p)
+            }
+            Checker::TcpChecker(p) => {
+                write!(f, "{}", p)
+            }
+            Checker::TlsChecker(p) => {
+                write!(f, "{}", p)
+            }
         }
     }
 }
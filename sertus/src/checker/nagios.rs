@@ -0,0 +1,309 @@
+use tracing::warn;
+
+use super::Status;
+use crate::metric_ext::{MetricStruct, MetricValue};
+
+/// status of a plugin exit code, anything other than 0 to 3 is unknown
+pub fn status(exit_code: Option<i32>) -> Status {
+    match exit_code {
+        Some(0) => Status::Ok,
+        Some(1) => Status::Warning,
+        Some(2) => Status::Critical,
+        _ => Status::Unknown,
+    }
+}
+
+/// split a plugin output into its text and performance data,
+/// `TEXT | PERFDATA` on the first line, then long text lines until a line with `|`,
+/// everything after that `|` is performance data
+pub fn split_output(output: &str) -> (String, Vec<String>) {
+    let mut text = vec![];
+    let mut perfdata = vec![];
+    let mut lines = output.lines();
+    if let Some(first) = lines.next() {
+        let (first_text, first_perf) = first.split_once('|').unwrap_or((first, ""));
+        text.push(first_text.trim());
+        perfdata.push(first_perf.to_string());
+    }
+    let mut in_perfdata = false;
+    for line in lines {
+        if in_perfdata {
+            perfdata.push(line.to_string());
+        } else if let Some((long_text, perf)) = line.split_once('|') {
+            text.push(long_text.trim_end());
+            perfdata.push(perf.to_string());
+            in_perfdata = true;
+        } else {
+            text.push(line.trim_end());
+        }
+    }
+    let text = text.join("\n").trim().to_string();
+    let perfdata = perfdata
+        .iter()
+        .flat_map(|line| split_perfdata(line))
+        .collect();
+    (text, perfdata)
+}
+
+/// split perfdata items by spaces, a quoted label may contain spaces and `''` for a quote
+fn split_perfdata(line: &str) -> Vec<String> {
+    let mut items = vec![];
+    let mut item = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' if quoted && chars.peek() == Some(&'\'') => {
+                item.push(c);
+                item.push(chars.next().unwrap_or('\''));
+            }
+            '\'' => {
+                quoted = !quoted;
+                item.push(c);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !item.is_empty() {
+                    items.push(std::mem::take(&mut item));
+                }
+            }
+            c => item.push(c),
+        }
+    }
+    if !item.is_empty() {
+        items.push(item);
+    }
+    items
+}
+
+/// A threshold range `[@][start:][end]`, `~` is negative infinity,
+/// `@` alerts inside the range instead of outside
+#[derive(Debug, Clone, PartialEq)]
+pub struct Range {
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub inside: bool,
+}
+
+impl Range {
+    fn parse(s: &str) -> Option<Self> {
+        let (inside, s) = match s.strip_prefix('@') {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+        let (start, end) = match s.split_once(':') {
+            Some((start, end)) => (start, end),
+            None => ("0", s),
+        };
+        let start = match start {
+            "~" => None,
+            "" => Some(0.0),
+            start => Some(start.parse().ok()?),
+        };
+        let end = match end {
+            "" => None,
+            end => Some(end.parse().ok()?),
+        };
+        Some(Self { start, end, inside })
+    }
+}
+
+/// A performance data item `'label'=value[UOM];[warn];[crit];[min];[max]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PerfData {
+    pub label: String,
+    pub value: f64,
+    pub uom: String,
+    pub warn: Option<Range>,
+    pub crit: Option<Range>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl PerfData {
+    pub fn parse(item: &str) -> Result<Self, String> {
+        let (label, data) = item
+            .rsplit_once('=')
+            .ok_or_else(|| format!("{:?} has no '='", item))?;
+        let label = match label.strip_prefix('\'').and_then(|l| l.strip_suffix('\'')) {
+            Some(label) => label.replace("''", "'"),
+            None => label.to_string(),
+        };
+        let mut fields = data.split(';');
+        let value = fields.next().unwrap_or_default();
+        let number_end = value
+            .find(|c: char| !(c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | 'e' | 'E')))
+            .unwrap_or(value.len());
+        let (number, uom) = value.split_at(number_end);
+        let value = number
+            .parse::<f64>()
+            .map_err(|_| format!("{:?} has no numeric value", item))?;
+        let mut range = |name: &str| match fields.next().unwrap_or_default() {
+            "" => Ok(None),
+            field => Range::parse(field)
+                .map(Some)
+                .ok_or_else(|| format!("{:?} has an invalid {} threshold", item, name)),
+        };
+        let warn = range("warning")?;
+        let crit = range("critical")?;
+        let mut number = |name: &str| match fields.next().unwrap_or_default() {
+            "" => Ok(None),
+            field => field
+                .parse::<f64>()
+                .map(Some)
+                .map_err(|_| format!("{:?} has an invalid {}", item, name)),
+        };
+        let min = number("min")?;
+        let max = number("max")?;
+        Ok(Self {
+            label,
+            value,
+            uom: uom.to_string(),
+            warn,
+            crit,
+            min,
+            max,
+        })
+    }
+
+    /// metric name suffix and scale of the unit, e.g. `ms` is reported in seconds
+    fn unit(&self) -> (&'static str, f64) {
+        match self.uom.as_str() {
+            "s" => ("_seconds", 1.0),
+            "ms" => ("_seconds", 1e-3),
+            "us" => ("_seconds", 1e-6),
+            "%" => ("_percent", 1.0),
+            "B" => ("_bytes", 1.0),
+            "KB" => ("_bytes", 1024.0),
+            "MB" => ("_bytes", 1024.0 * 1024.0),
+            "GB" => ("_bytes", 1024.0 * 1024.0 * 1024.0),
+            "TB" => ("_bytes", 1024.0 * 1024.0 * 1024.0 * 1024.0),
+            "c" => ("_total", 1.0),
+            _ => ("", 1.0),
+        }
+    }
+
+    /// gauges `nagios_perfdata[_unit]` of the value, the bounds of thresholds, min and max,
+    /// scaled to base units, the perfdata label is the `label` label
+    pub fn metrics(&self, labels: &[(String, String)]) -> Vec<MetricStruct> {
+        let (suffix, scale) = self.unit();
+        let name = format!("nagios_perfdata{}", suffix);
+        let mut labels = labels.to_vec();
+        labels.push(("label".to_string(), self.label.clone()));
+        let gauge = |name: String, value: f64| MetricStruct {
+            name,
+            typ: "gauge".to_string(),
+            labels: labels.clone(),
+            value: MetricValue::F64(value * scale),
+        };
+        let mut metrics = vec![gauge(name.clone(), self.value)];
+        for (threshold, range) in [("warning", &self.warn), ("critical", &self.crit)] {
+            let Some(range) = range else {
+                continue;
+            };
+            if let Some(end) = range.end {
+                metrics.push(gauge(format!("{}_{}", name, threshold), end));
+            }
+            if let Some(start) = range.start.filter(|start| *start != 0.0) {
+                metrics.push(gauge(format!("{}_{}_low", name, threshold), start));
+            }
+        }
+        for (bound, value) in [("min", self.min), ("max", self.max)] {
+            if let Some(value) = value {
+                metrics.push(gauge(format!("{}_{}", name, bound), value));
+            }
+        }
+        metrics
+    }
+}
+
+/// metrics of all performance data items, invalid items are skipped with a warning
+pub fn perfdata_metrics(items: &[String], labels: &[(String, String)]) -> Vec<MetricStruct> {
+    items
+        .iter()
+        .filter_map(|item| {
+            PerfData::parse(item)
+                .inspect_err(|e| warn!("invalid perfdata {}", e))
+                .ok()
+        })
+        .flat_map(|perfdata| perfdata.metrics(labels))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_output() {
+        let output = "DISK OK - free space: / 3326 MB | /=2643MB;5948;5958;0;5968\n\
+                      / 15272 MB (77%);\n\
+                      /boot 68 MB (69%); | /boot=68MB;88;93;0;98\n\
+                      'home dir'=69%;80:;~:90 time=12ms";
+        let (text, perfdata) = split_output(output);
+        assert_eq!(
+            text,
+            "DISK OK - free space: / 3326 MB\n/ 15272 MB (77%);\n/boot 68 MB (69%);"
+        );
+        assert_eq!(
+            perfdata,
+            vec![
+                "/=2643MB;5948;5958;0;5968",
+                "/boot=68MB;88;93;0;98",
+                "'home dir'=69%;80:;~:90",
+                "time=12ms"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_perfdata() {
+        let perfdata = PerfData::parse("'home dir'=69%;80:;@~:90;0;100").unwrap();
+        assert_eq!(perfdata.label, "home dir");
+        assert_eq!(perfdata.value, 69.0);
+        assert_eq!(perfdata.uom, "%");
+        assert_eq!(
+            perfdata.warn,
+            Some(Range {
+                start: Some(80.0),
+                end: None,
+                inside: false
+            })
+        );
+        assert_eq!(
+            perfdata.crit,
+            Some(Range {
+                start: None,
+                end: Some(90.0),
+                inside: true
+            })
+        );
+        let names = perfdata
+            .metrics(&[])
+            .into_iter()
+            .map(|m| m.name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "nagios_perfdata_percent",
+                "nagios_perfdata_percent_warning_low",
+                "nagios_perfdata_percent_critical",
+                "nagios_perfdata_percent_min",
+                "nagios_perfdata_percent_max"
+            ]
+        );
+
+        let metrics = PerfData::parse("time=12ms;100").unwrap().metrics(&[]);
+        assert_eq!(metrics[0].name, "nagios_perfdata_seconds");
+        assert_eq!(
+            metrics[0].labels,
+            vec![("label".to_string(), "time".to_string())]
+        );
+        assert_eq!(metrics[0].value, MetricValue::F64(0.012));
+        assert_eq!(metrics[1].value, MetricValue::F64(0.1));
+
+        assert!(PerfData::parse("time").is_err());
+        assert!(PerfData::parse("time=U").is_err());
+        assert!(PerfData::parse("time=1;x").is_err());
+    }
+}
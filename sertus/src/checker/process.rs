@@ -4,6 +4,7 @@ use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use tokio::process::Command;
 
+use super::CheckResult;
 use crate::executor::Executor;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -27,7 +28,7 @@ impl Display for ProcessChecker {
 
 #[async_trait]
 impl Executor for ProcessChecker {
-    type Output = (bool, String);
+    type Output = CheckResult;
     async fn exec(&self) -> crate::error::Result<Self::Output> {
         let output = Command::new("ps")
             .arg("-eo")
@@ -36,16 +37,23 @@ impl Executor for ProcessChecker {
             .await
             .unwrap();
         let content = String::from_utf8_lossy(&output.stdout);
-        let lines = content.trim().split("\n");
+        let lines = content.trim().split('\n');
         let processes = lines
             .into_iter()
             .filter(|s| *s != "COMMAND")
             .filter(|s| s.starts_with(&self.prefix))
             .collect::<Vec<_>>();
-        if output.stderr.len() > 0 {
-            return Ok((false, String::from_utf8_lossy(&output.stderr).into_owned()));
-        }
-        Ok((processes.len() > 0, processes.join("\n")))
+        let mut result = if !output.stderr.is_empty() {
+            CheckResult::critical(String::from_utf8_lossy(&output.stderr).trim())
+        } else if processes.is_empty() {
+            CheckResult::critical(format!("no process starts with {:?}", self.prefix))
+        } else {
+            CheckResult::ok(processes.join("\n"))
+        };
+        result.stdout = content.into_owned();
+        result.stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        result.exit_code = output.status.code();
+        Ok(result)
     }
 }
 
@@ -64,17 +72,17 @@ mod tests {
             .unwrap();
         assert_eq!(Some(0), output.status.code());
         let content = String::from_utf8_lossy(&output.stdout);
-        let lines = content.trim().split("\n");
+        let lines = content.trim().split('\n');
         let process = lines
             .into_iter()
             .filter(|s| s.starts_with("time ps -eo command"))
             .collect::<Vec<_>>();
         assert_eq!(1, process.len());
-        assert!(process.get(0).unwrap().starts_with("time ps -eo command"));
+        assert!(process.first().unwrap().starts_with("time ps -eo command"));
     }
     #[tokio::test]
     async fn test_process_checker() {
         let checker = ProcessChecker::new("");
-        assert!(checker.exec().await.unwrap().0);
+        assert!(checker.exec().await.unwrap().is_ok());
     }
 }
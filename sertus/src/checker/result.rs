@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Result,
+    exposition::MetricFamily,
+    metric_ext::{LabelExtractor, MetricExtractor, MetricStruct},
+};
+
+/// Status of a check, the states of a Nagios plugin with timeout
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    #[default]
+    Ok,
+    Warning,
+    Critical,
+    Unknown,
+    Timeout,
+}
+
+impl Status {
+    pub fn is_ok(&self) -> bool {
+        *self == Status::Ok
+    }
+}
+
+/// Result of a single checker run
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct CheckResult {
+    pub status: Status,
+    /// Short summary of the check, e.g. the reason of a failure
+    pub message: String,
+    pub stdout: String,
+    pub stderr: String,
+    /// Exit code of a command, none for network checkers or a killed command
+    pub exit_code: Option<i32>,
+    /// Time the checker took, set by `Checker`
+    pub duration: Duration,
+    /// Labels added to the metrics of the task
+    pub labels: Vec<(String, String)>,
+    pub metrics: Vec<MetricStruct>,
+    /// Series of a Prometheus exposition output, re-exported with the flow and task labels
+    pub families: Vec<MetricFamily>,
+}
+
+impl CheckResult {
+    pub fn new(status: Status, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn ok(message: impl Into<String>) -> Self {
+        Self::new(Status::Ok, message)
+    }
+
+    pub fn critical(message: impl Into<String>) -> Self {
+        Self::new(Status::Critical, message)
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status.is_ok()
+    }
+
+    /// extract `#label` and `#metric` directives of an output into labels and metrics
+    pub fn extract_directives(&mut self, output: &str) -> Result<()> {
+        let output = output.to_string();
+        self.labels.extend(output.extract_label()?);
+        self.metrics.extend(output.extract_metric()?);
+        Ok(())
+    }
+}
+
+/// lines of an output which are not `#label` or `#metric` directives
+pub fn without_directives(output: &str) -> String {
+    output
+        .lines()
+        .filter(|line| !line.starts_with("#label") && !line.starts_with("#metric"))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_directives() -> Result<()> {
+        let output = "#label {k=v}\n#metric up gauge {a=b} 1\nall good\n";
+        let mut result = CheckResult::ok(without_directives(output));
+        result.extract_directives(output)?;
+        assert_eq!(result.message, "all good");
+        assert_eq!(result.labels, vec![("k".to_string(), "v".to_string())]);
+        assert_eq!(result.metrics[0].name, "up");
+        assert!(result.is_ok());
+        Ok(())
+    }
+}
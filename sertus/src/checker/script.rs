@@ -1,15 +1,54 @@
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    fs::File,
+    os::unix::{fs::PermissionsExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::{Command as StdCommand, Output, Stdio},
+};
 
 use async_trait::async_trait;
+use home::home_dir;
 use serde::{Deserialize, Serialize};
 use tokio::process::Command;
 
-use crate::executor::Executor;
+use super::{json, nagios, result::without_directives, CheckResult, Status};
+use crate::{app_error, executor::Executor, exposition};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ScriptChecker {
     pub path: String,
+    /// Interpreter of the script, default bash, or none in nagios mode to run the plugin itself
     pub bin: Option<String>,
+    /// Arguments passed to the script
+    pub args: Option<Vec<String>>,
+    /// How the output is interpreted, default sertus
+    pub mode: Option<ScriptMode>,
+    /// Protocol of the output in sertus mode, default text
+    pub output: Option<ScriptOutput>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptMode {
+    /// fails on any stderr or a non-zero exit code, metrics are `#metric` lines
+    #[default]
+    Sertus,
+    /// a Nagios plugin, exit codes 0 to 3 are ok, warning, critical and unknown,
+    /// metrics are the performance data after `|`
+    Nagios,
+    /// stdout is Prometheus text or OpenMetrics, re-exported with the flow and task labels,
+    /// fails like sertus mode, or is unknown when stdout can not be parsed
+    Prometheus,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptOutput {
+    /// the message with `#label` and `#metric` lines
+    #[default]
+    Text,
+    /// a single JSON document with the status, message, labels and metrics
+    Json,
 }
 
 impl ScriptChecker {
@@ -17,6 +56,46 @@ impl ScriptChecker {
         Self {
             path: path.into(),
             bin: Some("bash".to_string()),
+            args: None,
+            mode: None,
+            output: None,
+        }
+    }
+
+    /// a Nagios plugin run by itself
+    pub fn nagios(path: impl Into<String>) -> Self {
+        Self {
+            bin: None,
+            mode: Some(ScriptMode::Nagios),
+            ..Self::new(path)
+        }
+    }
+
+    /// resolve a relative path against `dir`, e.g. the config dir, unless it is a command
+    /// looked up in PATH, which is a bare name run by itself
+    pub fn resolve(&mut self, dir: &Path) {
+        let path = self.script_path();
+        if path.is_relative() && (self.interpreter().is_some() || self.path.contains('/')) {
+            self.path = dir.join(path).to_string_lossy().into_owned();
+        }
+    }
+
+    /// the path with a leading `~` expanded to the home directory
+    fn script_path(&self) -> PathBuf {
+        match (self.path.strip_prefix('~'), home_dir()) {
+            (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+                home.join(rest.trim_start_matches('/'))
+            }
+            _ => PathBuf::from(&self.path),
+        }
+    }
+
+    /// the interpreter of the script, none to run the script itself
+    fn interpreter(&self) -> Option<&str> {
+        match (&self.bin, self.mode.unwrap_or_default()) {
+            (Some(bin), _) => Some(bin),
+            (None, ScriptMode::Sertus | ScriptMode::Prometheus) => Some("bash"),
+            (None, ScriptMode::Nagios) => None,
         }
     }
 }
@@ -27,29 +106,194 @@ impl Display for ScriptChecker {
     }
 }
 
+/// Kills the whole process group of a script when dropped,
+/// e.g. the exec future is dropped by a timeout.
+struct ProcessGroupGuard(Option<i32>);
+
+impl ProcessGroupGuard {
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        if let Some(pgid) = self.0 {
+            unsafe {
+                libc::killpg(pgid, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+impl ScriptChecker {
+    /// check the script is a readable file and its interpreter is executable
+    pub fn validate(&self) -> crate::error::Result<()> {
+        let path = self.script_path();
+        if !path.is_file() {
+            return Err(app_error!("script {} does not exist", self.path));
+        }
+        File::open(&path).map_err(|e| app_error!("script {} is not readable: {}", self.path, e))?;
+        if self.output.unwrap_or_default() == ScriptOutput::Json
+            && self.mode.unwrap_or_default() != ScriptMode::Sertus
+        {
+            return Err(app_error!(
+                "script {} can not use json output in {:?} mode",
+                self.path,
+                self.mode.unwrap_or_default()
+            ));
+        }
+        match self.interpreter() {
+            Some(bin) if !is_executable(bin) => Err(app_error!(
+                "interpreter {} of script {} is not found or not executable",
+                bin,
+                self.path
+            )),
+            None if !is_executable(&path.to_string_lossy()) => {
+                Err(app_error!("script {} is not executable", self.path))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// the check result of a script output according to the mode
+    fn result(&self, output: &Output) -> crate::error::Result<CheckResult> {
+        match (
+            self.mode.unwrap_or_default(),
+            self.output.unwrap_or_default(),
+        ) {
+            (ScriptMode::Sertus, ScriptOutput::Json) => self.json_result(output),
+            (ScriptMode::Sertus, ScriptOutput::Text) => Self::sertus_result(output),
+            (ScriptMode::Nagios, _) => self.nagios_result(output),
+            (ScriptMode::Prometheus, _) => Self::prometheus_result(output),
+        }
+    }
+
+    /// the status is taken from the JSON document of stdout, stderr and the exit code are kept
+    /// for debugging, a document which does not match the schema is an error
+    fn json_result(&self, output: &Output) -> crate::error::Result<CheckResult> {
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let mut result = json::parse(&stdout)
+            .map_err(|e| app_error!("invalid JSON output of script {}: {}", self.path, e))?;
+        result.stdout = stdout;
+        result.stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        result.exit_code = output.status.code();
+        Ok(result)
+    }
+
+    fn prometheus_result(output: &Output) -> crate::error::Result<CheckResult> {
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        let mut result = match exposition::parse(&stdout) {
+            Ok(families) => {
+                let mut result = if !stderr.is_empty() {
+                    CheckResult::critical(stderr.trim())
+                } else if output.status.success() {
+                    CheckResult::ok(format!("{} metric families", families.len()))
+                } else {
+                    CheckResult::critical(format!("exit code {:?}", output.status.code()))
+                };
+                result.families = families;
+                result
+            }
+            Err(e) => CheckResult::new(Status::Unknown, format!("invalid exposition, {}", e)),
+        };
+        result.stdout = stdout;
+        result.stderr = stderr;
+        result.exit_code = output.status.code();
+        Ok(result)
+    }
+
+    /// the exit code is the status, the first line and long text before `|` are the message,
+    /// metrics of the performance data are labeled by the plugin name
+    fn nagios_result(&self, output: &Output) -> crate::error::Result<CheckResult> {
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let (text, perfdata) = nagios::split_output(&stdout);
+        let plugin = Path::new(&self.path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut result = CheckResult::new(
+            nagios::status(output.status.code()),
+            without_directives(&text),
+        );
+        result.metrics = nagios::perfdata_metrics(&perfdata, &[("plugin".to_string(), plugin)]);
+        result.extract_directives(&text)?;
+        result.stdout = stdout;
+        result.stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        result.exit_code = output.status.code();
+        Ok(result)
+    }
+
+    /// anything on stderr fails the check, directives are extracted from both stdout and stderr
+    fn sertus_result(output: &Output) -> crate::error::Result<CheckResult> {
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        let mut result = if !stderr.is_empty() {
+            CheckResult::critical(without_directives(&stderr))
+        } else if output.status.success() {
+            CheckResult::ok(without_directives(&stdout))
+        } else {
+            CheckResult::critical(without_directives(&stdout))
+        };
+        result.extract_directives(&stdout)?;
+        result.extract_directives(&stderr)?;
+        result.stdout = stdout;
+        result.stderr = stderr;
+        result.exit_code = output.status.code();
+        Ok(result)
+    }
+}
+
+/// an executable file, looked up in PATH unless it is a path
+fn is_executable(bin: &str) -> bool {
+    let executable = |path: &Path| {
+        path.metadata()
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+    if bin.contains('/') {
+        return executable(Path::new(bin));
+    }
+    std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).any(|dir| executable(&dir.join(bin))))
+        .unwrap_or(false)
+}
+
 #[async_trait]
 impl Executor for ScriptChecker {
-    type Output = (bool, String);
+    type Output = CheckResult;
     async fn exec(&self) -> crate::error::Result<Self::Output> {
-        let output = Command::new(self.bin.clone().unwrap_or("bash".to_string()))
-            .arg(self.path.clone())
-            .output()
-            .await
-            .unwrap();
-        let content = String::from_utf8_lossy(&output.stdout);
-        if output.stderr.len() > 0 {
-            return Ok((false, String::from_utf8_lossy(&output.stderr).into_owned()));
-        }
-        Ok((output.status.success(), content.to_string()))
+        let mut command = match self.interpreter() {
+            Some(bin) => {
+                let mut command = StdCommand::new(bin);
+                command.arg(self.script_path());
+                command
+            }
+            None => StdCommand::new(self.script_path()),
+        };
+        // run the script in its own process group, so it can be killed with its children
+        command
+            .args(self.args.iter().flatten())
+            .process_group(0)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let child = Command::from(command).kill_on_drop(true).spawn()?;
+        let guard = ProcessGroupGuard(child.id().map(|id| id as i32));
+        let output = child.wait_with_output().await?;
+        guard.disarm();
+        self.result(&output)
     }
 }
 #[cfg(test)]
 mod tests {
-    use std::io::Write;
+    use std::{io::Write, time::Duration};
 
     use tempfile::NamedTempFile;
 
-    use crate::{executor::Executor, pkg::log::init_tracing};
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::{checker::Status, executor::Executor, pkg::log::init_tracing};
 
     use super::ScriptChecker;
 
@@ -62,9 +306,144 @@ mod tests {
         script_file.write_all(script_content.as_bytes())?;
 
         let checker = ScriptChecker::new(script_file.path().to_str().ok_or("path to str failed")?);
-        assert!(checker.exec().await?.0);
+        let result = checker.exec().await?;
+        assert!(result.is_ok());
+        assert_eq!(result.message, "Hello, world!");
+        assert_eq!(result.exit_code, Some(0));
         // remove temp file
         script_file.close()?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_script_resolve() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("scripts"))?;
+        std::fs::write(
+            dir.path().join("scripts/check.sh"),
+            "#!/bin/bash\necho ok\n",
+        )?;
+
+        let mut checker = ScriptChecker::new("scripts/check.sh");
+        assert!(checker.validate().is_err());
+        checker.resolve(dir.path());
+        checker.validate()?;
+        assert_eq!(checker.exec().await?.message, "ok");
+
+        // `~` is expanded, a command looked up in PATH is kept
+        let mut checker = ScriptChecker::new("~/check.sh");
+        checker.resolve(dir.path());
+        assert_eq!(checker.path, "~/check.sh");
+        assert!(!checker.script_path().starts_with("~"));
+        let mut checker = ScriptChecker::nagios("true");
+        checker.resolve(dir.path());
+        assert_eq!(checker.path, "true");
+        Ok(())
+    }
+
+    fn is_alive(pid: &str) -> bool {
+        // an orphaned zombie may not be reaped in a container
+        std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .map(|stat| !stat.contains(") Z "))
+            .unwrap_or(false)
+    }
+
+    #[tokio::test]
+    async fn test_script_checker_timeout_kills_group() -> Result<(), Box<dyn std::error::Error>> {
+        let pid_file = NamedTempFile::new()?;
+        let mut script_file = NamedTempFile::new()?;
+        let script_content = format!(
+            "#!/bin/bash\nsleep 30 &\necho $! > {}\nwait\n",
+            pid_file.path().display()
+        );
+        script_file.write_all(script_content.as_bytes())?;
+
+        let checker = ScriptChecker::new(script_file.path().to_str().ok_or("path to str failed")?);
+        let result = tokio::time::timeout(Duration::from_millis(500), checker.exec()).await;
+        assert!(result.is_err());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let pid = std::fs::read_to_string(pid_file.path())?;
+        assert!(!pid.trim().is_empty());
+        assert!(!is_alive(pid.trim()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nagios_plugin() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let plugin = dir.path().join("check_disk");
+        std::fs::write(
+            &plugin,
+            "#!/bin/sh\necho \"DISK WARNING - $1 | /=2643MB;2000;3000;0;5968\"\necho \"long text\"\nexit 1\n",
+        )?;
+        std::fs::set_permissions(&plugin, std::fs::Permissions::from_mode(0o755))?;
+        let mut checker = ScriptChecker::nagios(plugin.to_str().ok_or("path to str failed")?);
+        checker.args = Some(vec!["/".to_string()]);
+        checker.validate()?;
+        let result = checker.exec().await?;
+        assert_eq!(result.status, Status::Warning);
+        assert_eq!(result.message, "DISK WARNING - /\nlong text");
+        assert_eq!(result.exit_code, Some(1));
+        assert_eq!(result.metrics[0].name, "nagios_perfdata_bytes");
+        assert_eq!(
+            result.metrics[0].labels,
+            vec![
+                ("plugin".to_string(), "check_disk".to_string()),
+                ("label".to_string(), "/".to_string())
+            ]
+        );
+        let used: f64 = result.metrics[0].value.clone().into();
+        assert_eq!(used, 2643.0 * 1024.0 * 1024.0);
+
+        std::fs::write(&plugin, "#!/bin/sh\necho \"timeout\"\nexit 7\n")?;
+        assert_eq!(checker.exec().await?.status, Status::Unknown);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prometheus_script() -> Result<(), Box<dyn std::error::Error>> {
+        let mut script_file = NamedTempFile::new()?;
+        script_file
+            .write_all(b"echo '# TYPE jobs counter'\necho 'jobs_total{queue=\"a,b\"} 3'\n")?;
+        let mut checker =
+            ScriptChecker::new(script_file.path().to_str().ok_or("path to str failed")?);
+        checker.mode = Some(super::ScriptMode::Prometheus);
+        let result = checker.exec().await?;
+        assert!(result.is_ok(), "{}", result.message);
+        assert_eq!(result.families[0].name, "jobs");
+        assert_eq!(result.families[0].samples[0].labels[0].1, "a,b");
+
+        std::fs::write(script_file.path(), "echo 'jobs_total{queue=a} 3'\n")?;
+        assert_eq!(checker.exec().await?.status, Status::Unknown);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_json_output() -> Result<(), Box<dyn std::error::Error>> {
+        let mut script_file = NamedTempFile::new()?;
+        script_file.write_all(
+            br#"echo '{"status": "critical", "message": "down", "labels": {"k": "v"}}'
+echo 'some log' >&2
+"#,
+        )?;
+        let mut checker =
+            ScriptChecker::new(script_file.path().to_str().ok_or("path to str failed")?);
+        checker.output = Some(super::ScriptOutput::Json);
+        checker.validate()?;
+        let result = checker.exec().await?;
+        assert_eq!(result.status, Status::Critical);
+        assert_eq!(result.message, "down");
+        assert_eq!(result.stderr, "some log\n");
+
+        std::fs::write(
+            script_file.path(),
+            "echo '{\"status\": \"ok\", \"msg\": \"\"}'\n",
+        )?;
+        let err = checker.exec().await.unwrap_err().to_string();
+        assert!(err.contains("unknown field `msg`"), "{}", err);
+
+        checker.mode = Some(super::ScriptMode::Nagios);
+        assert!(checker.validate().is_err());
+        Ok(())
+    }
 }
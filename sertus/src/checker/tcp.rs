@@ -0,0 +1,164 @@
+use std::{fmt::Display, time::Duration};
+
+use async_trait::async_trait;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{timeout_at, Instant},
+};
+
+use super::{CheckResult, Status};
+use crate::{app_error, error::Result, executor::Executor, metric_ext::MetricStruct};
+
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TcpChecker {
+    pub host: String,
+    pub port: u16,
+    /// Timeout of the whole probe, default 5(s)
+    pub timeout: Option<u64>,
+    /// Payload written after connected
+    pub send: Option<String>,
+    /// Regex the banner or response must match
+    pub expect: Option<String>,
+}
+
+impl TcpChecker {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            timeout: None,
+            send: None,
+            expect: None,
+        }
+    }
+
+    /// check the expect regex without a probe
+    pub fn validate(&self) -> Result<()> {
+        if let Some(expect) = &self.expect {
+            Regex::new(expect).map_err(|e| app_error!("invalid expect {:?}: {}", expect, e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for TcpChecker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tcp: {}:{}", self.host, self.port)
+    }
+}
+
+#[async_trait]
+impl Executor for TcpChecker {
+    type Output = CheckResult;
+    async fn exec(&self) -> Result<Self::Output> {
+        let expect = self.expect.as_deref().map(Regex::new).transpose()?;
+        let start = Instant::now();
+        let deadline = start + Duration::from_secs(self.timeout.unwrap_or(5));
+        let mut stream = match timeout_at(
+            deadline,
+            TcpStream::connect((self.host.as_str(), self.port)),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => return Ok(CheckResult::critical(format!("connect failed: {}", e))),
+            Err(_) => return Ok(CheckResult::new(Status::Timeout, "connect timed out")),
+        };
+        let connect = MetricStruct::gauge(
+            "tcp_connect_seconds",
+            vec![
+                ("host".to_string(), self.host.clone()),
+                ("port".to_string(), self.port.to_string()),
+            ],
+            start.elapsed().as_secs_f64(),
+        );
+        let result = |mut result: CheckResult| {
+            result.metrics.push(connect.clone());
+            Ok(result)
+        };
+        if let Some(send) = &self.send {
+            if let Err(e) = stream.write_all(send.as_bytes()).await {
+                return result(CheckResult::critical(format!("send failed: {}", e)));
+            }
+        }
+        let Some(expect) = expect else {
+            return result(CheckResult::ok("connected"));
+        };
+        // read until the response matches, the peer closes or the deadline
+        let mut response = vec![];
+        let mut buf = [0u8; 4096];
+        let mut reason = String::new();
+        loop {
+            if expect.is_match(&String::from_utf8_lossy(&response)) {
+                let mut matched = CheckResult::ok(format!("response matches /{}/", expect));
+                matched.stdout = String::from_utf8_lossy(&response).into_owned();
+                return result(matched);
+            }
+            match timeout_at(deadline, stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 && response.len() < MAX_RESPONSE_SIZE => {
+                    response.extend_from_slice(&buf[..n])
+                }
+                Ok(Ok(_)) => break,
+                Ok(Err(e)) => {
+                    reason = format!(", read failed: {}", e);
+                    break;
+                }
+                Err(_) => {
+                    reason = ", read timed out".to_string();
+                    break;
+                }
+            }
+        }
+        let mut failed =
+            CheckResult::critical(format!("response does not match /{}/{}", expect, reason));
+        failed.stdout = String::from_utf8_lossy(&response).into_owned();
+        result(failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tcp_checker() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 64];
+                    let n = socket.read(&mut buf).await.unwrap_or(0);
+                    if &buf[..n] == b"PING\r\n" {
+                        socket.write_all(b"+PONG\r\n").await.ok();
+                    }
+                });
+            }
+        });
+
+        let mut checker = TcpChecker::new("127.0.0.1", port);
+        assert!(checker.exec().await.unwrap().is_ok());
+
+        checker.send = Some("PING\r\n".to_string());
+        checker.expect = Some(r"^\+PONG".to_string());
+        let result = checker.exec().await.unwrap();
+        assert!(result.is_ok(), "{}", result.message);
+        assert_eq!(result.metrics[0].name, "tcp_connect_seconds");
+
+        checker.send = Some("QUIT\r\n".to_string());
+        checker.timeout = Some(1);
+        assert!(!checker.exec().await.unwrap().is_ok());
+
+        assert!(checker.validate().is_ok());
+        checker.expect = Some(r"^\+PONG(".to_string());
+        assert!(checker.validate().is_err());
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    fmt::Display,
+    fs,
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use openssl::{
+    asn1::Asn1Time,
+    error::ErrorStack,
+    nid::Nid,
+    ssl::{SslConnector, SslMethod, SslVerifyMode},
+    x509::{
+        store::X509StoreBuilder, X509NameRef, X509Ref, X509StoreContext, X509VerifyResult, X509,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use super::CheckResult;
+use crate::{app_error, error::Result, executor::Executor, metric_ext::MetricStruct};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TlsChecker {
+    /// Host to handshake with
+    pub host: Option<String>,
+    /// default 443
+    pub port: Option<u16>,
+    /// Server name used for SNI and hostname verification, default host
+    pub server_name: Option<String>,
+    /// PEM file to read instead of a handshake
+    pub path: Option<String>,
+    /// Fail when the certificate expires within days, default 14
+    pub warning_days: Option<u64>,
+    /// Fail when the chain does not validate, default true
+    pub verify: Option<bool>,
+    /// Handshake timeout, default 5(s)
+    pub timeout: Option<u64>,
+}
+
+impl TlsChecker {
+    pub fn new(host: impl Into<String>) -> Self {
+        Self {
+            host: Some(host.into()),
+            port: None,
+            server_name: None,
+            path: None,
+            warning_days: None,
+            verify: None,
+            timeout: None,
+        }
+    }
+
+    pub fn from_path(path: impl Into<String>) -> Self {
+        Self {
+            host: None,
+            path: Some(path.into()),
+            ..Self::new("")
+        }
+    }
+
+    /// returns the leaf certificate and the verify result of the chain
+    fn handshake(&self, host: &str) -> Result<(X509, X509VerifyResult)> {
+        let timeout = Duration::from_secs(self.timeout.unwrap_or(5));
+        let addr = (host, self.port.unwrap_or(443))
+            .to_socket_addrs()
+            .map_err(|e| app_error!("can not resolve {}: {}", host, e))?
+            .next()
+            .ok_or_else(|| app_error!("can not resolve {}", host))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)
+            .map_err(|e| app_error!("connect {}: {}", addr, e))?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let mut connector = SslConnector::builder(SslMethod::tls())?;
+        // complete the handshake anyway, the verify result is checked later
+        connector.set_verify(SslVerifyMode::NONE);
+        let stream = connector
+            .build()
+            .connect(self.server_name.as_deref().unwrap_or(host), stream)
+            .map_err(|e| app_error!("tls handshake: {}", e))?;
+        let cert = stream
+            .ssl()
+            .peer_certificate()
+            .ok_or_else(|| app_error!("no peer certificate"))?;
+        Ok((cert, stream.ssl().verify_result()))
+    }
+
+    fn read_pem(&self, path: &str) -> Result<(X509, X509VerifyResult)> {
+        let pem = fs::read(path).map_err(|e| app_error!("read {}: {}", path, e))?;
+        let mut certs = X509::stack_from_pem(&pem)
+            .map_err(|e| app_error!("parse pem {}: {}", path, e))?
+            .into_iter();
+        let cert = certs
+            .next()
+            .ok_or_else(|| app_error!("no certificate in {}", path))?;
+        let verify = || -> std::result::Result<X509VerifyResult, ErrorStack> {
+            let mut chain = openssl::stack::Stack::new()?;
+            for c in certs {
+                chain.push(c)?;
+            }
+            let mut store = X509StoreBuilder::new()?;
+            store.set_default_paths()?;
+            let store = store.build();
+            let mut context = X509StoreContext::new()?;
+            context.init(&store, &cert, &chain, |c| {
+                c.verify_cert()?;
+                Ok(c.error())
+            })
+        };
+        let result = verify().map_err(|e| app_error!("verify {}: {}", path, e))?;
+        Ok((cert, result))
+    }
+}
+
+/// the common name, or the organization, without label separators
+fn name_of(name: &X509NameRef) -> String {
+    name.entries_by_nid(Nid::COMMONNAME)
+        .chain(name.entries_by_nid(Nid::ORGANIZATIONNAME))
+        .find_map(|e| e.data().as_utf8().ok())
+        .map(|s| s.replace([',', '=', '{', '}'], " ").trim().to_string())
+        .unwrap_or_default()
+}
+
+fn days_until_expiry(cert: &X509Ref) -> Result<f64> {
+    let diff = Asn1Time::days_from_now(0)?.diff(cert.not_after())?;
+    Ok(diff.days as f64 + diff.secs as f64 / 86400.0)
+}
+
+impl Display for TlsChecker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.host, &self.path) {
+            (Some(host), _) => write!(f, "tls: {}:{}", host, self.port.unwrap_or(443)),
+            (None, path) => write!(f, "tls: {}", path.as_deref().unwrap_or_default()),
+        }
+    }
+}
+
+#[async_trait]
+impl Executor for TlsChecker {
+    type Output = CheckResult;
+    async fn exec(&self) -> Result<Self::Output> {
+        let checker = self.clone();
+        let loaded = tokio::task::spawn_blocking(move || match (&checker.host, &checker.path) {
+            (Some(host), _) => checker.handshake(host),
+            (None, Some(path)) => checker.read_pem(path),
+            (None, None) => Err(app_error!("either host or path is required")),
+        })
+        .await
+        .map_err(|e| app_error!("{}", e))?;
+        let (cert, verify_result) = match loaded {
+            Ok(loaded) => loaded,
+            // a host that can not be reached is a failure of the service
+            Err(e) if self.host.is_some() => return Ok(CheckResult::critical(e.to_string())),
+            Err(e) => return Err(e),
+        };
+
+        let days = days_until_expiry(&cert)?;
+        let verified = verify_result == X509VerifyResult::OK;
+        let labels = vec![
+            ("subject".to_string(), name_of(cert.subject_name())),
+            ("issuer".to_string(), name_of(cert.issuer_name())),
+        ];
+        let warning_days = self.warning_days.unwrap_or(14);
+        let mut result = if days < warning_days as f64 {
+            CheckResult::critical(format!(
+                "certificate expires in {:.1} days (warning window {} days)",
+                days, warning_days
+            ))
+        } else if !verified && self.verify.unwrap_or(true) {
+            CheckResult::critical(format!(
+                "certificate verify failed: {}",
+                verify_result.error_string()
+            ))
+        } else {
+            CheckResult::ok(format!("certificate expires in {:.1} days", days))
+        };
+        result.metrics = vec![
+            MetricStruct::gauge("tls_cert_expiry_days", labels.clone(), days),
+            MetricStruct::gauge("tls_cert_verified", labels.clone(), verified as u8 as f64),
+        ];
+        result.labels = labels;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        pkey::{PKey, Private},
+        ssl::SslAcceptor,
+        x509::X509NameBuilder,
+    };
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn self_signed(days: u32) -> (PKey<Private>, X509) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "sertus.test")
+            .unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(days).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (key, builder.build())
+    }
+
+    #[tokio::test]
+    async fn test_tls_checker_pem() -> Result<()> {
+        let mut pem_file = NamedTempFile::new()?;
+        pem_file.write_all(&self_signed(30).1.to_pem()?)?;
+        let mut checker = TlsChecker::from_path(pem_file.path().to_str().unwrap());
+
+        // self signed certificate does not validate
+        let result = checker.exec().await?;
+        assert!(!result.is_ok());
+        assert!(result
+            .labels
+            .contains(&("subject".to_string(), "sertus.test".to_string())));
+        assert_eq!(result.metrics[0].name, "tls_cert_expiry_days");
+        let days: f64 = result.metrics[0].value.clone().into();
+        assert!(days > 29.0 && days <= 30.0);
+
+        checker.verify = Some(false);
+        assert!(checker.exec().await?.is_ok());
+
+        checker.warning_days = Some(60);
+        assert!(!checker.exec().await?.is_ok());
+
+        // errors name the file and the cause
+        let path = pem_file.path().to_str().unwrap();
+        fs::write(
+            path,
+            "-----BEGIN CERTIFICATE-----\n!\n-----END CERTIFICATE-----\n",
+        )?;
+        let err = checker.exec().await.unwrap_err().to_string();
+        assert!(err.starts_with(&format!("parse pem {}: ", path)), "{}", err);
+        let missing = TlsChecker::from_path(format!("{}.missing", path));
+        let err = missing.exec().await.unwrap_err().to_string();
+        assert!(
+            err.starts_with(&format!("read {}.missing: ", path)),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tls_checker_handshake() -> Result<()> {
+        let (key, cert) = self_signed(30);
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+        acceptor.set_private_key(&key)?;
+        acceptor.set_certificate(&cert)?;
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = acceptor.accept(stream);
+            }
+        });
+        let mut checker = TlsChecker::new("127.0.0.1");
+        checker.port = Some(port);
+        checker.verify = Some(false);
+        let result = checker.exec().await?;
+        assert!(result.is_ok(), "{}", result.message);
+        assert!(result
+            .labels
+            .contains(&("issuer".to_string(), "sertus.test".to_string())));
+
+        // a server which does not speak TLS fails the handshake
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        checker.port = Some(listener.local_addr()?.port());
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.read(&mut [0; 1024]);
+                let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+            }
+        });
+        let result = checker.exec().await?;
+        assert!(!result.is_ok());
+        assert!(
+            result.message.contains("tls handshake"),
+            "{}",
+            result.message
+        );
+
+        // a closed port is a failure, not an error of the checker
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        checker.port = Some(port);
+        let result = checker.exec().await?;
+        assert!(!result.is_ok());
+        assert!(result.metrics.is_empty());
+        Ok(())
+    }
+}
@@ -1,5 +1,13 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
 use std::future::Future;
-use std::{fs, path::PathBuf, sync::RwLock};
+use std::str::FromStr;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::SystemTime,
+};
 
 use home::home_dir;
 use once_cell::sync::{Lazy, OnceCell};
@@ -7,21 +15,30 @@ use sconfig::{Configurable, FileType, Toml};
 use serde::{Deserialize, Serialize};
 use tracing::{error, info};
 
+use crate::app_error;
 use crate::flow::Flow;
 use crate::metrics::Metrics;
+use crate::notifier::Notifier;
 
-const CONFIG_PATH: Lazy<PathBuf> = Lazy::new(|| {
+static CONFIG_PATH: Lazy<PathBuf> = Lazy::new(|| {
     let mut sertus_path = home_dir().unwrap().join(".sertus");
     if let Some(env_sertus_path) = std::env::var_os("SERTUS_PATH") {
         sertus_path = PathBuf::from(env_sertus_path);
     };
     sertus_path
 });
+const FLOWS_DIR: &str = "flows.d";
 pub(crate) static CONFIG: OnceCell<RwLock<Option<Config>>> = OnceCell::new();
 
-#[derive(Serialize, Deserialize, Debug, Clone, Toml)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Toml)]
 pub struct Config {
+    /// Max checkers running at the same time across all flows, default unlimited
+    pub max_concurrency: Option<usize>,
+    /// Seconds to wait for running checkers on shutdown before killing them, default 10
+    pub shutdown_timeout: Option<u64>,
     pub metrics: Metrics,
+    /// Targets notified when a task changes state
+    pub notifiers: Option<Vec<Notifier>>,
     pub flows: Vec<Flow>,
 }
 
@@ -35,48 +52,438 @@ impl Configurable for Config {
     }
 }
 
+/// Flows of a file in the flows.d directory
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Toml)]
+pub struct FlowsConfig {
+    #[serde(default)]
+    pub flows: Vec<Flow>,
+}
+
+/// A problem of a config file, at the position it is found when known
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub path: PathBuf,
+    /// 1-based line and column
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.position {
+            Some((line, column)) => write!(
+                f,
+                "{}:{}:{}: {}",
+                self.path.display(),
+                line,
+                column,
+                self.message
+            ),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+/// read and validate the config file and flow files
+pub fn read() -> crate::error::Result<Config> {
+    read_dir(&CONFIG_PATH)
+}
+
+/// problems of the config file and flow files, empty when the config is valid
+pub fn diagnostics() -> Vec<Diagnostic> {
+    diagnose(&CONFIG_PATH).1
+}
+
+/// read config.toml and every *.toml in flows.d of `dir`,
+/// errors report the files and positions they come from
+pub fn read_dir(dir: &Path) -> crate::error::Result<Config> {
+    match diagnose(dir) {
+        (Some(config), diagnostics) if diagnostics.is_empty() => Ok(config),
+        (_, diagnostics) => Err(app_error!(
+            "Invalid config\n{}",
+            diagnostics
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join("\n")
+        )),
+    }
+}
+
+/// read config.toml and every *.toml in flows.d of `dir` and check them,
+/// the config is none when a file can not be parsed
+pub fn diagnose(dir: &Path) -> (Option<Config>, Vec<Diagnostic>) {
+    let mut diagnostics = vec![];
+    let config_path = dir.join(Config::default().config_name());
+    let mut config = match parse_file::<Config>(&config_path) {
+        Ok((config, source)) => {
+            if let Err(e) = config.metrics.validate() {
+                let value = match &config.metrics {
+                    Metrics::Server(s) => &s.addr,
+                    Metrics::PushGateway(p) => &p.endpoint,
+                };
+                diagnostics.push(Diagnostic {
+                    position: locate(&source, &[value], 0),
+                    path: config_path.clone(),
+                    message: e.to_string(),
+                });
+            }
+            // notifiers of the same name, to locate the duplicated one
+            let mut seen: HashMap<&str, usize> = HashMap::new();
+            for notifier in config.notifiers.iter().flatten() {
+                let nth = seen.entry(&notifier.name).or_default();
+                let problem = match *nth {
+                    0 => notifier
+                        .validate()
+                        .err()
+                        .map(|e| format!("Notifier({}) {}", notifier.name, e)),
+                    _ => Some(format!("duplicate Notifier({})", notifier.name)),
+                };
+                if let Some(message) = problem {
+                    diagnostics.push(Diagnostic {
+                        position: locate(&source, &[&notifier.name], *nth),
+                        path: config_path.clone(),
+                        message,
+                    });
+                }
+                *nth += 1;
+            }
+            Some((config, source))
+        }
+        Err(diagnostic) => {
+            diagnostics.push(diagnostic);
+            None
+        }
+    };
+    let mut files = vec![];
+    let mut flows = vec![];
+    if let Some((config, source)) = config.as_mut() {
+        flows.extend(
+            std::mem::take(&mut config.flows)
+                .into_iter()
+                .map(|f| (0, f)),
+        );
+        files.push((config_path, std::mem::take(source)));
+    }
+    for path in flow_files(dir) {
+        match parse_file::<FlowsConfig>(&path) {
+            Ok((file, source)) => {
+                flows.extend(file.flows.into_iter().map(|f| (files.len(), f)));
+                files.push((path, source));
+            }
+            Err(diagnostic) => diagnostics.push(diagnostic),
+        }
+    }
+
+    let mut sources: HashMap<String, usize> = HashMap::new();
+    // flows of the same name in a file, to locate the duplicated one
+    let mut seen: HashMap<(usize, String), usize> = HashMap::new();
+    let mut unique = vec![];
+    for (file, mut flow) in flows {
+        // script paths are relative to the config dir, not the working dir of the daemon
+        for task in flow.tasks.iter_mut() {
+            task.checker.resolve(dir);
+        }
+        let (path, source) = &files[file];
+        let nth = seen.entry((file, flow.name.clone())).or_default();
+        let position = |task: Option<&str>| match task {
+            Some(task) => locate(source, &[&flow.name, task], *nth),
+            None => locate(source, &[&flow.name], *nth),
+        };
+        if let Some(first) = sources.get(&flow.name) {
+            diagnostics.push(Diagnostic {
+                path: path.clone(),
+                position: position(None),
+                message: format!(
+                    "duplicate Flow({}), already defined in {}",
+                    flow.name,
+                    files[*first].0.display()
+                ),
+            });
+        } else {
+            for (task, e) in flow.diagnose() {
+                diagnostics.push(Diagnostic {
+                    path: path.clone(),
+                    position: position(task),
+                    message: e.to_string(),
+                });
+            }
+            sources.insert(flow.name.clone(), file);
+            unique.push(flow);
+        }
+        *nth += 1;
+    }
+    let config = config.map(|(mut config, _)| {
+        config.flows = unique;
+        config
+    });
+    (config, diagnostics)
+}
+
+/// parse a file, a parse error is located by the span of the toml error
+fn parse_file<T: FromStr<Err = toml::de::Error>>(
+    path: &Path,
+) -> std::result::Result<(T, String), Diagnostic> {
+    let diagnostic = |position, message| Diagnostic {
+        path: path.to_path_buf(),
+        position,
+        message,
+    };
+    let source = fs::read_to_string(path).map_err(|e| diagnostic(None, e.to_string()))?;
+    match source.parse::<T>() {
+        Ok(parsed) => Ok((parsed, source)),
+        Err(e) => Err(diagnostic(
+            e.span().map(|span| position(&source, span.start)),
+            e.message().trim().replace('\n', ", "),
+        )),
+    }
+}
+
+/// the position of the `nth` quoted `names[0]`, then each following name after the previous one,
+/// e.g. a task name after its flow name
+fn locate(source: &str, names: &[&str], nth: usize) -> Option<(usize, usize)> {
+    let mut offset = 0;
+    for (i, name) in names.iter().enumerate() {
+        let skip = if i == 0 { nth } else { 0 };
+        let find = |from: usize| {
+            [format!("\"{}\"", name), format!("'{}'", name)]
+                .iter()
+                .filter_map(|quoted| source[from..].find(quoted.as_str()).map(|p| from + p))
+                .min()
+        };
+        let mut found = find(offset)?;
+        for _ in 0..skip {
+            found = find(found + 1)?;
+        }
+        offset = found;
+    }
+    Some(position(source, offset))
+}
+
+/// 1-based line and column of a byte offset
+fn position(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before[before.rfind('\n').map_or(0, |p| p + 1)..]
+        .chars()
+        .count()
+        + 1;
+    (line, column)
+}
+
+/// *.toml files in the flows.d directory of `dir`, sorted by name
+pub fn flow_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = fs::read_dir(dir.join(FLOWS_DIR))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.is_file() && path.extension().map_or(false, |e| e == "toml"))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
 fn load() -> &'static RwLock<Option<Config>> {
     CONFIG.get_or_init(|| {
-        let config_path = Config::default().config_path();
-        info!("Loading config {}", config_path.display());
-        RwLock::new(
-            fs::read_to_string(&config_path)
-                .inspect_err(|e| error!("Read file {}: {}", &config_path.display(), e))
-                .ok()
-                .and_then(|v| {
-                    v.parse::<Config>()
-                        .inspect_err(|e| error!("Parse file {}: {}", &config_path.display(), e))
-                        .ok()
-                }),
-        )
+        info!(
+            "Loading config {}",
+            Config::default().config_path().display()
+        );
+        RwLock::new(read().inspect_err(|e| error!("{}", e)).ok())
     })
 }
 
+/// read the config again and replace the loaded one, which is kept on error
+pub fn reload() -> crate::error::Result<Config> {
+    let config = read()?;
+    *load().write().unwrap() = Some(config.clone());
+    Ok(config)
+}
+
+/// modified time of the config files, changes when any of them changes
+pub fn fingerprint() -> Vec<(PathBuf, Option<SystemTime>)> {
+    std::iter::once(Config::default().config_path())
+        .chain(flow_files(&CONFIG_PATH))
+        .map(|path| {
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+            (path, modified)
+        })
+        .collect()
+}
+
+/// the loaded config, or the error of reading it again when it is invalid
+fn loaded() -> crate::error::Result<Config> {
+    match load().read().unwrap().as_ref() {
+        Some(config) => Ok(config.clone()),
+        None => read(),
+    }
+}
+
 #[cfg(not(feature = "async_config"))]
-pub fn with_config<T, F>(f: impl FnOnce(&Config) -> T) -> T {
-    f(load().read().unwrap().as_ref().unwrap())
+pub fn with_config<T, F>(f: impl FnOnce(&Config) -> T) -> crate::error::Result<T> {
+    Ok(f(&loaded()?))
 }
 #[cfg(feature = "async_config")]
-pub async fn with_config<T, F, Fut>(f: F) -> T
+pub async fn with_config<T, F, Fut>(f: F) -> crate::error::Result<T>
 where
     F: FnOnce(Config) -> Fut,
     Fut: Future<Output = T>,
 {
-    f(load().read().unwrap().as_ref().unwrap().clone()).await
+    Ok(f(loaded()?).await)
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            flows: vec![],
-            metrics: Metrics::default(),
+impl Config {
+    pub fn validate(&self) -> crate::error::Result<()> {
+        self.metrics.validate()?;
+        let mut names = HashSet::new();
+        for notifier in self.notifiers.iter().flatten() {
+            if !names.insert(notifier.name.as_str()) {
+                return Err(app_error!("duplicate Notifier({})", notifier.name));
+            }
+            notifier
+                .validate()
+                .map_err(|e| app_error!("Notifier({}) {}", notifier.name, e))?;
+        }
+        let mut names = HashSet::new();
+        for flow in self.flows.iter() {
+            if !names.insert(flow.name.as_str()) {
+                return Err(app_error!("duplicate Flow({})", flow.name));
+            }
+            flow.validate()?;
         }
+        Ok(())
     }
-}
 
-impl Config {
     pub fn add_flow(&mut self, flow: Flow) -> &mut Self {
         self.flows.push(flow);
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[metrics.Server]
+addr = "127.0.0.1:9296"
+
+[[flows]]
+name = "flow 1"
+interval = 3
+
+[[flows.tasks]]
+name = "check process"
+checker.ProcessChecker = { prefix = "process prefix" }
+"#;
+
+    #[test]
+    fn test_read_dir() -> crate::error::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("config.toml"), CONFIG)?;
+        fs::create_dir(dir.path().join(FLOWS_DIR))?;
+        fs::write(
+            dir.path().join(FLOWS_DIR).join("team.toml"),
+            CONFIG[CONFIG.find("[[flows]]").unwrap()..].replace("flow 1", "flow 2"),
+        )?;
+        fs::write(
+            dir.path().join(FLOWS_DIR).join("README.md"),
+            "not a flow file",
+        )?;
+        let config = read_dir(dir.path())?;
+        let names = config
+            .flows
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["flow 1", "flow 2"]);
+
+        let duplicate = dir.path().join(FLOWS_DIR).join("other.toml");
+        fs::write(&duplicate, "[[flows]]\nname = \"flow 2\"\ntasks = []\n")?;
+        let err = read_dir(dir.path()).unwrap_err().to_string();
+        assert!(err.contains("duplicate Flow(flow 2)"), "{}", err);
+        assert!(err.contains("other.toml"), "{}", err);
+
+        fs::write(
+            &duplicate,
+            "[[flows]]\nname = \"flow 3\"\ninterval = 0\ntasks = []\n",
+        )?;
+        let err = read_dir(dir.path()).unwrap_err().to_string();
+        assert!(err.contains("other.toml"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_diagnose() -> crate::error::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(
+            dir.path().join("config.toml"),
+            CONFIG.replace("interval = 3", "interval = 3x"),
+        )?;
+        let (config, diagnostics) = diagnose(dir.path());
+        assert!(config.is_none());
+        assert_eq!(diagnostics[0].position, Some((7, 13)));
+
+        let script = dir.path().join("check.sh");
+        fs::write(&script, "#!/bin/bash\n")?;
+        let config = format!(
+            r#"[metrics.Server]
+addr = "localhost"
+
+[[notifiers]]
+name = "hook"
+target.Webhook = {{ url = "http://127.0.0.1/hook" }}
+
+[[notifiers]]
+name = "hook"
+target.Webhook = {{ url = "ftp://127.0.0.1/hook" }}
+
+[[flows]]
+name = "flow 1"
+interval = 0
+tasks = []
+
+[[flows]]
+name = "flow 2"
+
+[[flows.tasks]]
+name = "ok"
+checker.ScriptChecker = {{ path = "{}" }}
+
+[[flows.tasks]]
+name = "missing"
+checker.ScriptChecker = {{ path = "/no/such/script.sh" }}
+
+[[flows]]
+name = "flow 1"
+tasks = []
+"#,
+            script.display()
+        );
+        fs::write(dir.path().join("config.toml"), config)?;
+        let diagnostics = diagnose(dir.path())
+            .1
+            .into_iter()
+            .map(|d| (d.position.map(|(line, _)| line), d.message))
+            .collect::<Vec<_>>();
+        assert_eq!(diagnostics.len(), 5, "{:?}", diagnostics);
+        assert!(diagnostics[0].1.contains("invalid metrics addr"));
+        assert_eq!(diagnostics[0].0, Some(2));
+        assert_eq!(
+            diagnostics[1],
+            (Some(9), "duplicate Notifier(hook)".to_string())
+        );
+        assert!(diagnostics[2].1.contains("interval must be greater than 0"));
+        assert_eq!(diagnostics[2].0, Some(13));
+        assert!(diagnostics[3]
+            .1
+            .contains("/no/such/script.sh does not exist"));
+        assert_eq!(diagnostics[3].0, Some(25));
+        assert!(diagnostics[4].1.contains("duplicate Flow(flow 1)"));
+        assert_eq!(diagnostics[4].0, Some(29));
+        assert!(read_dir(dir.path()).is_err());
+        Ok(())
+    }
+}
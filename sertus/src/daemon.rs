@@ -0,0 +1,356 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    task::JoinHandle,
+    time::{interval, timeout_at, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config::{self, Config},
+    error::Result,
+    flow::{set_max_concurrency, Flow},
+    metrics::{
+        self, install_recorder, setup_pushgateway, shutdown_pushgateway, start_metrics_server,
+        Metrics,
+    },
+    notifier::{self, set_notifiers},
+};
+
+/// Running flows and metrics exporter of the daemon
+pub struct Daemon {
+    metrics: Option<(Metrics, JoinHandle<()>)>,
+    flows: HashMap<String, (Flow, JoinHandle<()>)>,
+    /// set to true to stop flows from scheduling new runs
+    shutdown: watch::Sender<bool>,
+}
+
+impl Default for Daemon {
+    fn default() -> Self {
+        Self {
+            metrics: None,
+            flows: HashMap::new(),
+            shutdown: watch::channel(false).0,
+        }
+    }
+}
+
+/// compare configs by their serialized form
+fn same<T: serde::Serialize>(a: &T, b: &T) -> bool {
+    match (serde_json::to_value(a), serde_json::to_value(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+impl Daemon {
+    /// apply a config: stop removed flows, start new ones, restart changed ones,
+    /// and restart the metrics exporter when it changed
+    pub async fn apply(&mut self, config: Config) {
+        set_max_concurrency(config.max_concurrency);
+        set_notifiers(config.notifiers.clone().unwrap_or_default());
+        if !matches!(&self.metrics, Some((m, _)) if same(m, &config.metrics)) {
+            if let Some((_, handle)) = self.metrics.take() {
+                info!("Restarting metrics exporter");
+                // the server must release its addr before it is bound again
+                handle.abort();
+                let _ = handle.await;
+            }
+            install_recorder(&config.metrics);
+            let handle = match config.metrics.clone() {
+                Metrics::Server(s) => tokio::spawn(start_metrics_server(s)),
+                Metrics::PushGateway(p) => tokio::spawn(setup_pushgateway(p)),
+            };
+            self.metrics = Some((config.metrics, handle));
+        }
+
+        let mut running = std::mem::take(&mut self.flows);
+        for flow in config.flows {
+            match running.remove(&flow.name) {
+                Some((old, handle)) if same(&old, &flow) => {
+                    self.flows.insert(flow.name.clone(), (old, handle));
+                }
+                Some((old, handle)) => {
+                    info!("Restarting changed Flow({})", flow.name);
+                    // the old flow must not record or notify once it is retired or replaced
+                    handle.abort();
+                    let _ = handle.await;
+                    for task in old.tasks.iter() {
+                        if !flow.tasks.iter().any(|t| t.name == task.name) {
+                            metrics::retire(&old.name, Some(&task.name));
+                        }
+                    }
+                    self.start(flow);
+                }
+                None => {
+                    info!("Starting Flow({})", flow.name);
+                    self.start(flow);
+                }
+            }
+        }
+        for (name, (_, handle)) in running {
+            info!("Stopping removed Flow({})", name);
+            handle.abort();
+            let _ = handle.await;
+            metrics::retire(&name, None);
+        }
+    }
+
+    fn start(&mut self, flow: Flow) {
+        let tasks = flow
+            .tasks
+            .iter()
+            .map(|t| t.name.as_str())
+            .collect::<Vec<_>>();
+        metrics::unretire(&flow.name, &tasks);
+        let mut shutdown = self.shutdown.subscribe();
+        let handle = tokio::spawn(flow.clone().run_until(async move {
+            // the sender lives as long as the daemon
+            let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+        }));
+        self.flows.insert(flow.name.clone(), (flow, handle));
+    }
+
+    /// names of running flows
+    pub fn flows(&self) -> Vec<&str> {
+        self.flows.keys().map(|name| name.as_str()).collect()
+    }
+
+    /// stop scheduling new runs and wait up to `grace` for running tasks,
+    /// remaining tasks are aborted which kills their child processes,
+    /// then pending digests and notifications in flight are sent within what is left of `grace`,
+    /// returns false when any task had to be aborted or a notification was dropped
+    pub async fn shutdown(&mut self, grace: Duration) -> bool {
+        self.shutdown.send_replace(true);
+        let mut flows = std::mem::take(&mut self.flows)
+            .into_iter()
+            .map(|(name, (_, handle))| (name, handle))
+            .collect::<Vec<_>>();
+        let deadline = Instant::now() + grace;
+        let mut clean = true;
+        for (name, handle) in flows.iter_mut() {
+            if timeout_at(deadline, &mut *handle).await.is_err() {
+                warn!(
+                    "Flow({}) is still running after {:?}, killing it",
+                    name, grace
+                );
+                handle.abort();
+                let _ = handle.await;
+                clean = false;
+            }
+        }
+        // the state changes of the last runs may wait in digests or be in flight
+        if timeout_at(deadline, notifier::flush()).await.is_err() {
+            warn!(
+                "Notifications are still sending after {:?}, dropping them",
+                grace
+            );
+            clean = false;
+        }
+        if let Some((metrics, handle)) = self.metrics.take() {
+            handle.abort();
+            if let Metrics::PushGateway(p) = metrics {
+                if let Err(e) = shutdown_pushgateway(&p).await {
+                    error!("{}", e);
+                    clean = false;
+                }
+            }
+        }
+        clean
+    }
+}
+
+/// run the daemon, reload the config on SIGHUP, or on file change when `watch`,
+/// shut down gracefully on SIGTERM or SIGINT, returns false when the shutdown was not clean
+pub async fn run(watch: bool) -> Result<bool> {
+    let config = config::reload()?;
+    debug!("With config: {:#?}", config);
+    let mut grace = Duration::from_secs(config.shutdown_timeout.unwrap_or(10));
+    let mut daemon = Daemon::default();
+    daemon.apply(config).await;
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut ticker = interval(Duration::from_secs(2));
+    let mut fingerprint = config::fingerprint();
+    loop {
+        tokio::select! {
+            _ = terminate.recv() => {
+                info!("Received SIGTERM, shutting down");
+                break;
+            }
+            _ = interrupt.recv() => {
+                info!("Received SIGINT, shutting down");
+                break;
+            }
+            _ = hangup.recv() => info!("Received SIGHUP, reloading config"),
+            _ = ticker.tick(), if watch => {
+                let current = config::fingerprint();
+                if current == fingerprint {
+                    continue;
+                }
+                fingerprint = current;
+                info!("Config changed, reloading config");
+            }
+        }
+        match config::reload() {
+            Ok(config) => {
+                grace = Duration::from_secs(config.shutdown_timeout.unwrap_or(10));
+                daemon.apply(config).await;
+            }
+            Err(e) => error!("Reload config: {}, keep running the previous config", e),
+        }
+    }
+    let clean = daemon.shutdown(grace).await;
+    info!("Daemon stopped");
+    Ok(clean)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        checker::{process::ProcessChecker, script::ScriptChecker, Checker},
+        metrics::Server,
+        task::Task,
+    };
+
+    fn config(flows: &[(&str, &[&str])]) -> Config {
+        let mut config = Config {
+            metrics: Metrics::Server(Server {
+                addr: "127.0.0.1:0".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        for (name, tasks) in flows {
+            let mut flow = Flow::new(*name);
+            for task in tasks.iter() {
+                flow.add_task(Task::new(
+                    *task,
+                    Checker::ProcessChecker(ProcessChecker::new("")),
+                ));
+            }
+            config.add_flow(flow);
+        }
+        config
+    }
+
+    #[tokio::test]
+    async fn test_daemon_apply() {
+        let mut daemon = Daemon::default();
+        daemon
+            .apply(config(&[("f1", &["t1"]), ("f2", &["t1"])]))
+            .await;
+        let mut flows = daemon.flows();
+        flows.sort();
+        assert_eq!(flows, vec!["f1", "f2"]);
+        let f1 = daemon.flows["f1"].1.id();
+        let f2 = daemon.flows["f2"].1.id();
+        let exporter = daemon.metrics.as_ref().unwrap().1.id();
+
+        daemon
+            .apply(config(&[("f1", &["t1"]), ("f2", &["t2"]), ("f3", &["t1"])]))
+            .await;
+        let mut flows = daemon.flows();
+        flows.sort();
+        assert_eq!(flows, vec!["f1", "f2", "f3"]);
+        // unchanged flow and exporter keep running, changed flow is restarted
+        assert_eq!(daemon.flows["f1"].1.id(), f1);
+        assert_ne!(daemon.flows["f2"].1.id(), f2);
+        assert_eq!(daemon.metrics.as_ref().unwrap().1.id(), exporter);
+
+        daemon.apply(config(&[("f3", &["t1"])])).await;
+        assert_eq!(daemon.flows(), vec!["f3"]);
+    }
+
+    #[tokio::test]
+    async fn test_daemon_restart_exporter() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let mut config = config(&[]);
+        config.metrics = Metrics::Server(Server {
+            addr: addr.to_string(),
+            ..Default::default()
+        });
+        let mut daemon = Daemon::default();
+        daemon.apply(config.clone()).await;
+        let url = format!("http://{}/metrics", addr);
+        for bucket in ["restart_a", "restart_b"] {
+            config.metrics = Metrics::Server(Server {
+                addr: addr.to_string(),
+                bucket: Some(bucket.to_string()),
+                ..Default::default()
+            });
+            // the changed exporter binds the same addr again
+            daemon.apply(config.clone()).await;
+            let mut response = None;
+            for _ in 0..20 {
+                match reqwest::get(&url).await {
+                    Ok(r) => {
+                        response = Some(r);
+                        break;
+                    }
+                    Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+                }
+            }
+            assert!(response
+                .ok_or("metrics server is not listening")?
+                .status()
+                .is_success());
+        }
+        daemon.shutdown(Duration::from_secs(1)).await;
+        Ok(())
+    }
+
+    fn is_alive(pid: &str) -> bool {
+        std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .map(|stat| !stat.contains(") Z "))
+            .unwrap_or(false)
+    }
+
+    #[tokio::test]
+    async fn test_daemon_shutdown() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let pid_file = dir.path().join("pid");
+        let script = dir.path().join("slow.sh");
+        std::fs::write(
+            &script,
+            format!("#!/bin/bash\necho $$ > {}\nsleep 30\n", pid_file.display()),
+        )?;
+        let mut config = config(&[]);
+        let mut flow = Flow::new("shutdown_f1");
+        flow.add_task(Task::new(
+            "slow",
+            Checker::ScriptChecker(ScriptChecker::new(script.to_str().unwrap())),
+        ));
+        config.add_flow(flow);
+
+        let mut daemon = Daemon::default();
+        daemon.apply(config).await;
+        while !pid_file.exists() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let pid = std::fs::read_to_string(&pid_file)?.trim().to_string();
+        assert!(is_alive(&pid));
+
+        // the script outlives the grace period and is killed
+        assert!(!daemon.shutdown(Duration::from_millis(200)).await);
+        assert!(daemon.flows().is_empty());
+        for _ in 0..20 {
+            if !is_alive(&pid) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(!is_alive(&pid));
+
+        // an idle daemon stops at once
+        let mut daemon = Daemon::default();
+        daemon.apply(self::config(&[])).await;
+        assert!(daemon.shutdown(Duration::from_secs(5)).await);
+        Ok(())
+    }
+}
@@ -26,6 +26,8 @@ pub enum AppError {
     Config(#[from] sconfig::error::ConfigError),
     #[error("regex error")]
     Regex(#[from] regex::Error),
+    #[error("openssl error")]
+    Openssl(#[from] openssl::error::ErrorStack),
 }
 
 #[macro_export]
@@ -0,0 +1,385 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{app_error, error::Result, metric_ext::unescape};
+
+/// Type of a metric family, OpenMetrics types without a Prometheus equivalent are untyped
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FamilyType {
+    Counter,
+    Gauge,
+    Histogram,
+    Summary,
+    #[default]
+    Untyped,
+}
+
+impl FamilyType {
+    fn parse(typ: &str) -> Self {
+        match typ {
+            "counter" => FamilyType::Counter,
+            "gauge" => FamilyType::Gauge,
+            "histogram" => FamilyType::Histogram,
+            "summary" => FamilyType::Summary,
+            _ => FamilyType::Untyped,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FamilyType::Counter => "counter",
+            FamilyType::Gauge => "gauge",
+            FamilyType::Histogram => "histogram",
+            FamilyType::Summary => "summary",
+            FamilyType::Untyped => "untyped",
+        }
+    }
+
+    /// suffixes of sample names which belong to a family of the type
+    fn suffixes(&self) -> &'static [&'static str] {
+        match self {
+            FamilyType::Counter => &["_total", "_created"],
+            FamilyType::Histogram => &["_bucket", "_sum", "_count", "_created"],
+            FamilyType::Summary => &["_sum", "_count", "_created"],
+            FamilyType::Gauge | FamilyType::Untyped => &[],
+        }
+    }
+}
+
+/// Series of a metric, e.g. the buckets, sum and count of a histogram
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct MetricFamily {
+    pub name: String,
+    pub help: Option<String>,
+    pub typ: FamilyType,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+/// parse Prometheus text or OpenMetrics exposition, timestamps and exemplars are dropped
+pub fn parse(text: &str) -> Result<Vec<MetricFamily>> {
+    let mut families: Vec<MetricFamily> = vec![];
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let error = |reason: &str| app_error!("line {}: {}: {}", index + 1, reason, line);
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut parts = comment.trim_start().splitn(3, ' ');
+            let (keyword, name, rest) = (parts.next(), parts.next(), parts.next());
+            match (keyword, name) {
+                (Some("EOF"), None) => break,
+                (Some("TYPE"), Some(name)) => {
+                    let typ = FamilyType::parse(rest.unwrap_or_default().trim());
+                    family(&mut families, name).typ = typ;
+                }
+                (Some("HELP"), Some(name)) => {
+                    let help = unescape(rest.unwrap_or_default());
+                    family(&mut families, name).help = Some(help);
+                }
+                // other comments and # UNIT are ignored
+                _ => {}
+            }
+            continue;
+        }
+        let sample = parse_sample(line).map_err(|reason| error(&reason))?;
+        // `_created` of OpenMetrics is a timestamp, not a series
+        if sample.name.ends_with("_created") {
+            continue;
+        }
+        let owner = families.iter().rposition(|f| {
+            f.name == sample.name
+                || f.typ
+                    .suffixes()
+                    .iter()
+                    .any(|suffix| sample.name.strip_suffix(suffix) == Some(f.name.as_str()))
+        });
+        match owner {
+            Some(owner) => families[owner].samples.push(sample),
+            None => families.push(MetricFamily {
+                name: sample.name.clone(),
+                samples: vec![sample],
+                ..Default::default()
+            }),
+        }
+    }
+    families.retain(|f| !f.samples.is_empty());
+    Ok(families)
+}
+
+/// the last family of `name`, or a new one
+fn family<'a>(families: &'a mut Vec<MetricFamily>, name: &str) -> &'a mut MetricFamily {
+    if families.last().map_or(true, |f| f.name != name) {
+        families.push(MetricFamily {
+            name: name.to_string(),
+            ..Default::default()
+        });
+    }
+    families.last_mut().unwrap()
+}
+
+fn is_name_char(c: char, first: bool) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == ':' || (!first && c.is_ascii_digit())
+}
+
+pub(crate) fn parse_sample(line: &str) -> std::result::Result<Sample, String> {
+    let name_end = line
+        .char_indices()
+        .find(|(i, c)| !is_name_char(*c, *i == 0))
+        .map_or(line.len(), |(i, _)| i);
+    if name_end == 0 {
+        return Err("invalid metric name".to_string());
+    }
+    let name = line[..name_end].to_string();
+    let mut rest = &line[name_end..];
+    let mut labels = vec![];
+    if let Some(label_text) = rest.strip_prefix('{') {
+        let (parsed, end) = parse_label_set(label_text)?;
+        labels = parsed;
+        rest = &label_text[end..];
+    }
+    // value, an optional timestamp, and an optional exemplar of OpenMetrics
+    let rest = rest.split(" # ").next().unwrap_or_default();
+    let mut fields = rest.split_whitespace();
+    let value = fields
+        .next()
+        .ok_or_else(|| "missing value".to_string())
+        .and_then(parse_value)?;
+    if let Some(timestamp) = fields.next() {
+        timestamp
+            .parse::<f64>()
+            .map_err(|_| format!("invalid timestamp {:?}", timestamp))?;
+    }
+    if fields.next().is_some() {
+        return Err("unexpected content after the timestamp".to_string());
+    }
+    Ok(Sample {
+        name,
+        labels,
+        value,
+    })
+}
+
+/// labels until the closing `}`, returns the labels and the length consumed
+fn parse_label_set(text: &str) -> std::result::Result<(Vec<(String, String)>, usize), String> {
+    let mut labels = vec![];
+    let mut chars = text.char_indices().peekable();
+    loop {
+        while chars
+            .next_if(|(_, c)| c.is_whitespace() || *c == ',')
+            .is_some()
+        {}
+        let Some((start, c)) = chars.next() else {
+            return Err("unclosed label set".to_string());
+        };
+        if c == '}' {
+            return Ok((labels, start + 1));
+        }
+        if !is_name_char(c, true) {
+            return Err(format!("invalid label name at {:?}", &text[start..]));
+        }
+        let mut end = start + c.len_utf8();
+        while let Some((i, c)) = chars.next_if(|(_, c)| is_name_char(*c, false)) {
+            end = i + c.len_utf8();
+        }
+        let key = text[start..end].to_string();
+        while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        if chars.next().map(|(_, c)| c) != Some('=') {
+            return Err(format!("label {} has no '='", key));
+        }
+        while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        if chars.next().map(|(_, c)| c) != Some('"') {
+            return Err(format!("value of label {} is not quoted", key));
+        }
+        let mut value = String::new();
+        loop {
+            match chars.next() {
+                Some((_, '"')) => break,
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, c)) => value.push(c),
+                    None => return Err(format!("unclosed value of label {}", key)),
+                },
+                Some((_, c)) => value.push(c),
+                None => return Err(format!("unclosed value of label {}", key)),
+            }
+        }
+        labels.push((key, value));
+    }
+}
+
+fn parse_value(value: &str) -> std::result::Result<f64, String> {
+    match value {
+        "+Inf" | "Inf" => Ok(f64::INFINITY),
+        "-Inf" => Ok(f64::NEG_INFINITY),
+        "NaN" => Ok(f64::NAN),
+        value => value
+            .parse::<f64>()
+            .map_err(|_| format!("invalid value {:?}", value)),
+    }
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// render families in the Prometheus text format, families of the same name are merged,
+/// `labels` are added to every sample, and a sample label of the same key is renamed `exported_<key>`
+pub fn render<'a>(
+    families: impl IntoIterator<Item = (&'a MetricFamily, Vec<(String, String)>)>,
+) -> String {
+    let mut merged: BTreeMap<String, (Option<&str>, FamilyType, Vec<String>)> = BTreeMap::new();
+    for (family, labels) in families {
+        let mut name = family.name.clone();
+        // an OpenMetrics counter `x` has samples `x_total`
+        if family.typ == FamilyType::Counter
+            && family
+                .samples
+                .iter()
+                .all(|s| s.name == format!("{}_total", name))
+        {
+            name.push_str("_total");
+        }
+        let entry = merged
+            .entry(name)
+            .or_insert((family.help.as_deref(), family.typ, vec![]));
+        for sample in family.samples.iter() {
+            let mut all = labels.clone();
+            for (key, value) in sample.labels.iter() {
+                let key = match labels.iter().any(|(k, _)| k == key) {
+                    true => format!("exported_{}", key),
+                    false => key.clone(),
+                };
+                all.push((key, value.clone()));
+            }
+            let labels = all
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+                .collect::<Vec<_>>()
+                .join(",");
+            entry.2.push(format!(
+                "{}{{{}}} {}",
+                sample.name,
+                labels,
+                format_value(sample.value)
+            ));
+        }
+    }
+    let mut output = String::new();
+    for (name, (help, typ, lines)) in merged {
+        if let Some(help) = help {
+            let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+        }
+        let _ = writeln!(output, "# TYPE {} {}", name, typ.as_str());
+        for line in lines {
+            let _ = writeln!(output, "{}", line);
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = r#"
+# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027 1395066363000
+http_requests_total{method="post",path="/a,b=c",note="say \"hi\"\n"} 3
+# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.5"} 4773
+rpc_duration_seconds_sum 1.7560473e+07
+rpc_duration_seconds_count 2693
+# TYPE latency histogram
+latency_bucket{le="0.1"} 1 # {trace_id="abc"} 0.05
+latency_bucket{le="+Inf"} 2
+latency_sum 0.3
+latency_count 2
+latency_created 1.6e9
+# TYPE jobs counter
+jobs_total{flow="mine"} 5
+untyped_thing NaN
+# EOF
+ignored 1
+"#;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let families = parse(TEXT)?;
+        let names = families.iter().map(|f| f.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "http_requests_total",
+                "rpc_duration_seconds",
+                "latency",
+                "jobs",
+                "untyped_thing"
+            ]
+        );
+        assert_eq!(
+            families[0].samples[1].labels,
+            vec![
+                ("method".to_string(), "post".to_string()),
+                ("path".to_string(), "/a,b=c".to_string()),
+                ("note".to_string(), "say \"hi\"\n".to_string())
+            ]
+        );
+        assert_eq!(families[1].typ, FamilyType::Summary);
+        assert_eq!(families[1].samples.len(), 3);
+        assert_eq!(families[2].samples.len(), 4);
+        assert_eq!(families[2].samples[1].labels[0].1, "+Inf");
+        assert!(families[4].samples[0].value.is_nan());
+        assert_eq!(parse("m -Inf")?[0].samples[0].value, f64::NEG_INFINITY);
+
+        assert!(parse("metric{a=b} 1").is_err());
+        assert!(parse("metric{a=\"b\"} one").is_err());
+        let err = parse("ok 1\nmetric{a=\"b} 1").unwrap_err().to_string();
+        assert!(err.starts_with("line 2"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_render() -> Result<()> {
+        let families = parse(TEXT)?;
+        let labels = vec![
+            ("flow".to_string(), "f".to_string()),
+            ("task".to_string(), "t".to_string()),
+        ];
+        let rendered = render(families.iter().map(|f| (f, labels.clone())));
+        assert!(rendered.contains("# TYPE latency histogram\n"));
+        assert!(rendered.contains(r#"latency_bucket{flow="f",task="t",le="+Inf"} 2"#));
+        assert!(rendered.contains("# TYPE jobs_total counter\n"));
+        assert!(rendered.contains(r#"jobs_total{flow="f",task="t",exported_flow="mine"} 5"#));
+        assert!(rendered.contains(r#"note="say \"hi\"\n""#));
+        // the rendered text can be parsed again
+        assert_eq!(parse(&rendered)?.len(), families.len());
+        Ok(())
+    }
+}
@@ -1,28 +1,151 @@
-use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use tokio::time::sleep;
+use tokio::{
+    sync::Semaphore,
+    task::{Id, JoinSet},
+    time::{sleep, sleep_until, timeout},
+};
 use tracing::{debug, error, info, warn};
 
 use crate::{
+    app_error,
+    checker::CheckResult,
+    error::{AppError, Result},
     executor::Executor,
-    metric_ext::{LabelExtractor, MetricExtractor},
-    task::Task,
+    notifier::{self, Transition},
+    schedule::Scheduler,
+    task::{Outcome, Task, TaskState},
 };
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Flow {
     pub name: String,
+    /// Seconds between the end of a run and the start of the next run
+    #[serde(default = "default_interval")]
     pub interval: u64,
+    /// Count the interval from the start of a run, so the flow does not drift
+    pub fixed_rate: Option<bool>,
+    /// Cron expression, overrides the interval
+    pub schedule: Option<String>,
+    /// Timezone of the schedule like "Asia/Shanghai", default local
+    pub timezone: Option<String>,
+    /// Max random delay(s) added before each run
+    pub jitter: Option<u64>,
+    /// Default timeout of tasks in seconds, none means no timeout
+    pub timeout: Option<u64>,
+    /// How to run tasks of the flow, default sequential
+    pub mode: Option<Mode>,
+    /// Max tasks running at the same time in parallel mode, default unlimited
+    pub max_concurrency: Option<usize>,
     pub tasks: Vec<Task>,
 }
 
+fn default_interval() -> u64 {
+    3
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Sequential,
+    Parallel,
+}
+
+/// Limits checkers running at the same time across all flows
+static GLOBAL_SEMAPHORE: Lazy<RwLock<Option<GlobalSemaphore>>> = Lazy::new(|| RwLock::new(None));
+/// (max concurrency, semaphore)
+type GlobalSemaphore = (usize, Arc<Semaphore>);
+
+/// set the daemon-wide limit of running checkers, none means unlimited
+pub fn set_max_concurrency(max_concurrency: Option<usize>) {
+    let mut global = GLOBAL_SEMAPHORE.write().unwrap();
+    if global.as_ref().map(|(max, _)| *max) != max_concurrency {
+        *global = max_concurrency.map(|max| (max, Arc::new(Semaphore::new(max))));
+    }
+}
+
+/// Result of a single attempt of a task
+enum Attempt {
+    Done(CheckResult),
+    Error(AppError),
+    Timeout(u64),
+}
+
+impl Attempt {
+    fn outcome(&self) -> Outcome {
+        match self {
+            Attempt::Done(result) => result.status.into(),
+            Attempt::Error(_) => Outcome::Error,
+            Attempt::Timeout(_) => Outcome::Timeout,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Attempt::Done(result) => result.message.clone(),
+            Attempt::Error(e) => e.to_string(),
+            Attempt::Timeout(secs) => format!("timed out after {}s", secs),
+        }
+    }
+
+    /// stdout and stderr of the checker
+    fn output(&self) -> String {
+        match self {
+            Attempt::Done(result) => [result.stdout.trim_end(), result.stderr.trim_end()]
+                .into_iter()
+                .filter(|output| !output.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => String::new(),
+        }
+    }
+}
+
+/// A task with its own interval, or the tasks on the schedule of the flow when none
+struct Unit {
+    task: Option<String>,
+    scheduler: Scheduler,
+    /// planned instant of the current or the last run
+    planned: Instant,
+    /// none while the tasks are running
+    due: Option<Instant>,
+}
+
+impl Unit {
+    fn new(task: Option<String>, scheduler: Scheduler) -> Self {
+        let planned = scheduler.first();
+        let due = Some(planned + scheduler.jitter());
+        Self {
+            task,
+            scheduler,
+            planned,
+            due,
+        }
+    }
+}
+
 impl Flow {
     pub fn new(name: impl Into<String>) -> Self {
         Self {
             name: name.into(),
             tasks: vec![],
-            interval: 3,
+            interval: default_interval(),
+            fixed_rate: None,
+            schedule: None,
+            timezone: None,
+            jitter: None,
+            timeout: None,
+            mode: None,
+            max_concurrency: None,
         }
     }
     pub fn add_task(&mut self, task: Task) -> &mut Self {
@@ -35,48 +158,766 @@ impl Flow {
     /// 1.0 => success
     /// 0.0 => faliure
     /// -1.0 => error
+    /// -2.0 => timeout
+    /// -3.0 => flapping
+    /// -4.0 => skipped, a dependency did not succeed
     pub async fn run(self) {
-        let flow_lables: Vec<(String, String)> = vec![("flow".to_owned(), self.name.clone())];
+        self.run_until(std::future::pending()).await
+    }
+
+    /// run flow until `shutdown` completes, running tasks are finished before it returns,
+    /// tasks with their own interval run apart from the flow, so a slow task does not delay
+    /// the others, and a task does not start again while it is still running
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) {
+        tokio::pin!(shutdown);
+        let mut units = vec![];
+        if self.tasks.iter().any(|t| t.interval.is_none()) {
+            match self.scheduler() {
+                Ok(scheduler) => units.push(Unit::new(None, scheduler)),
+                Err(e) => {
+                    error!("Schedule Flow({}): {}", self.name, e);
+                    return;
+                }
+            }
+        }
+        for task in self.tasks.iter() {
+            if let Some(interval) = task.interval {
+                match self.task_scheduler(interval) {
+                    Ok(scheduler) => units.push(Unit::new(Some(task.name.clone()), scheduler)),
+                    Err(e) => {
+                        error!("Schedule Flow({}) Task({}): {}", self.name, task.name, e);
+                        return;
+                    }
+                }
+            }
+        }
+        let flow = Arc::new(self);
+        let semaphore = flow.semaphore();
+        let states: Arc<Mutex<HashMap<String, TaskState>>> = Arc::default();
+        // running units by the id of their tokio task
+        let mut running: HashMap<Id, usize> = HashMap::new();
+        let mut set: JoinSet<Duration> = JoinSet::new();
         loop {
-            debug!("Starting Flow({})", self.name);
-            for task in self.tasks.clone() {
-                let mut labels: Vec<(String, String)> = flow_lables.clone();
-                labels.push(("task".to_owned(), task.name.clone()));
-                debug!("Running Task({}), {:?}", task.name, task.checker);
-                match task.checker.exec().await {
-                    Ok((status, output)) => {
-                        // extract label from output
-                        labels.extend(
-                            output
-                                .extract_label()
-                                .inspect_err(|e| error!("extract label: {}", e))
-                                .unwrap_or_default(),
+            let next_due = units.iter().filter_map(|unit| unit.due).min();
+            tokio::select! {
+                biased;
+                _ = &mut shutdown => {
+                    while set.join_next().await.is_some() {}
+                    info!("Stopped Flow({})", flow.name);
+                    return;
+                }
+                Some(joined) = set.join_next_with_id() => {
+                    let (id, elapsed) = match joined {
+                        Ok((id, elapsed)) => (id, Some(elapsed)),
+                        Err(e) => {
+                            error!("Join Flow({}) tasks: {}", flow.name, e);
+                            (e.id(), None)
+                        }
+                    };
+                    let Some(index) = running.remove(&id) else {
+                        continue;
+                    };
+                    let unit = &mut units[index];
+                    if let (None, Some(elapsed)) = (&unit.task, elapsed) {
+                        metrics::gauge!(
+                            "sertus_flow_cycle_duration_seconds",
+                            elapsed.as_secs_f64(),
+                            "flow" => flow.name.clone()
                         );
-                        // extract metric from output
-                        output
-                            .extract_metric()
-                            .inspect_err(|e| error!("extract metric: {}", e))
-                            .map(|items| items.into_iter().for_each(|item| item.send()))
-                            .ok();
-                        debug!("metrics labels: {:?}", labels);
-                        if status {
-                            debug!("{:?}, stdout: {}", task.checker, output);
-                            info!("Succeeded Task({})", task.name);
-                            metrics::gauge!("sertus_flow_task_status", 1.0, &labels);
-                        } else {
-                            warn!("{:?}, stderr: {}", task.checker, output);
-                            warn!("Failed Task({})", task.name);
-                            metrics::gauge!("sertus_flow_task_status", 0.0, &labels);
+                    }
+                    unit.planned = unit.scheduler.next(unit.planned);
+                    unit.due = Some(unit.planned + unit.scheduler.jitter());
+                    continue;
+                }
+                _ = sleep_until(next_due.unwrap_or_else(Instant::now).into()), if next_due.is_some() => {}
+            }
+            let now = Instant::now();
+            for (index, unit) in units.iter_mut().enumerate() {
+                if unit.due.map_or(true, |due| due > now) {
+                    continue;
+                }
+                unit.due = None;
+                let tasks = flow
+                    .tasks
+                    .iter()
+                    .filter(|t| match &unit.task {
+                        Some(name) => t.name == *name,
+                        None => t.interval.is_none(),
+                    })
+                    .map(|t| t.name.clone())
+                    .collect::<Vec<_>>();
+                let flow = flow.clone();
+                let states = states.clone();
+                // the max concurrency of a parallel flow holds across its units,
+                // a sequential flow runs the tasks of each unit one by one
+                let semaphore = match flow.mode.unwrap_or_default() {
+                    Mode::Parallel => semaphore.clone(),
+                    Mode::Sequential => flow.semaphore(),
+                };
+                let handle = set.spawn(async move {
+                    let start = Instant::now();
+                    // dependencies out of the tasks are judged by a copy of their states
+                    let mut local = states.lock().unwrap().clone();
+                    let due = flow.tasks.iter().filter(|t| tasks.contains(&t.name));
+                    flow.run_tasks(due.collect(), &mut local, &semaphore).await;
+                    let mut states = states.lock().unwrap();
+                    for name in tasks {
+                        if let Some(state) = local.remove(&name) {
+                            states.insert(name, state);
                         }
                     }
-                    Err(e) => {
-                        metrics::gauge!("sertus_flow_task_status", -1.0, &labels);
-                        error!("Error Task({}), {}", task.name, e);
+                    start.elapsed()
+                });
+                running.insert(handle.id(), index);
+            }
+        }
+    }
+
+    /// run tasks of the flow once, or only `task` when some, without schedules,
+    /// returns the outcome of each task in the order of the config
+    pub async fn run_once(self, task: Option<&str>) -> Result<Vec<(String, Outcome)>> {
+        if let Some(name) = task {
+            if !self.tasks.iter().any(|t| t.name == name) {
+                return Err(app_error!("Flow({}) has no Task({})", self.name, name));
+            }
+        }
+        let flow = Arc::new(self);
+        let tasks = flow
+            .tasks
+            .iter()
+            .filter(|t| task.map_or(true, |name| t.name == name))
+            .collect::<Vec<_>>();
+        let mut outcomes = flow
+            .run_tasks(tasks, &mut HashMap::new(), &flow.semaphore())
+            .await;
+        Ok(flow
+            .tasks
+            .iter()
+            .filter_map(|t| outcomes.remove_entry(&t.name))
+            .collect())
+    }
+
+    pub fn scheduler(&self) -> Result<Scheduler> {
+        Scheduler::new(
+            self.interval,
+            self.schedule.as_deref(),
+            self.timezone.as_deref(),
+            self.fixed_rate.unwrap_or_default(),
+            self.jitter.unwrap_or_default(),
+        )
+    }
+
+    /// a task interval shares the fixed rate mode of the flow, without a cron expression
+    fn task_scheduler(&self, interval: u64) -> Result<Scheduler> {
+        Scheduler::new(interval, None, None, self.fixed_rate.unwrap_or_default(), 0)
+    }
+
+    /// permits of tasks running at the same time, one in sequential mode
+    fn semaphore(&self) -> Arc<Semaphore> {
+        let concurrency = match self.mode.unwrap_or_default() {
+            Mode::Sequential => 1,
+            Mode::Parallel => self.max_concurrency.unwrap_or(self.tasks.len()).max(1),
+        };
+        Arc::new(Semaphore::new(concurrency))
+    }
+
+    /// run tasks of the flow once in the order of dependencies, a dependency out of
+    /// the tasks is judged by its last reported outcome, returns the reported outcome of each task
+    async fn run_tasks(
+        self: &Arc<Self>,
+        tasks: Vec<&Task>,
+        states: &mut HashMap<String, TaskState>,
+        semaphore: &Arc<Semaphore>,
+    ) -> HashMap<String, Outcome> {
+        debug!("Starting Flow({})", self.name);
+        let mut outcomes: HashMap<String, Outcome> = HashMap::new();
+        let names: HashSet<&str> = tasks.iter().map(|t| t.name.as_str()).collect();
+        for dependency in tasks.iter().flat_map(|t| t.depends_on.iter().flatten()) {
+            if !names.contains(dependency.as_str()) {
+                // a dependency which has never run does not block
+                let reported = states.get(dependency).and_then(|s| s.reported);
+                outcomes.insert(dependency.clone(), reported.unwrap_or(Outcome::Success));
+            }
+        }
+        let mut pending: Vec<&Task> = tasks;
+        let mut set = JoinSet::new();
+        loop {
+            // start tasks whose dependencies have finished, a skipped task may unblock others
+            let mut progressed = true;
+            while progressed {
+                progressed = false;
+                let mut waiting = vec![];
+                for task in pending.drain(..) {
+                    let depends_on = task.depends_on.as_deref().unwrap_or_default();
+                    if !depends_on.iter().all(|d| outcomes.contains_key(d)) {
+                        waiting.push(task);
+                        continue;
+                    }
+                    progressed = true;
+                    if let Some(dependency) = depends_on
+                        .iter()
+                        .find(|d| outcomes[d.as_str()] != Outcome::Success)
+                    {
+                        let mut state = states.remove(&task.name).unwrap_or_default();
+                        self.skip_task(task, dependency, outcomes[dependency.as_str()], &mut state);
+                        states.insert(task.name.clone(), state);
+                        outcomes.insert(task.name.clone(), Outcome::Skipped);
+                        continue;
                     }
+                    let permit = semaphore.clone().acquire_owned().await;
+                    let flow = self.clone();
+                    let task = task.clone();
+                    let mut state = states.remove(&task.name).unwrap_or_default();
+                    set.spawn(async move {
+                        let _permit = permit;
+                        let outcome = flow.run_task(&task, &mut state).await;
+                        (task.name, state, outcome)
+                    });
+                }
+                pending = waiting;
+            }
+            match set.join_next().await {
+                Some(Ok((name, state, outcome))) => {
+                    outcomes.insert(name.clone(), outcome);
+                    states.insert(name, state);
+                }
+                Some(Err(e)) => error!("Join Flow({}) task: {}", self.name, e),
+                None => break,
+            }
+        }
+        // dependencies of these tasks never finished, e.g. panicked
+        for task in pending {
+            warn!("Skipped Task({}), dependencies never finished", task.name);
+            outcomes.insert(task.name.clone(), Outcome::Skipped);
+        }
+        debug!("Ended Flow({})", self.name);
+        outcomes.retain(|name, _| names.contains(name.as_str()));
+        outcomes
+    }
+
+    /// record a task skipped because of `dependency`, and notify the skip,
+    /// which resolves the alert of a failing task
+    fn skip_task(&self, task: &Task, dependency: &str, outcome: Outcome, state: &mut TaskState) {
+        info!(
+            "Skipped Task({}), dependency Task({}) is {:?}",
+            task.name, dependency, outcome
+        );
+        let labels = vec![
+            ("flow".to_owned(), self.name.clone()),
+            ("task".to_owned(), task.name.clone()),
+        ];
+        let previous = state.skip();
+        let mut transition = Transition::new(&self.name, &task.name, previous, Outcome::Skipped);
+        transition.message = format!("dependency Task({}) is {:?}", dependency, outcome);
+        transition.labels = labels.iter().cloned().collect();
+        if let Some(since) = state.since {
+            transition.since = DateTime::<Utc>::from(since).to_rfc3339();
+        }
+        notifier::notify(transition);
+        metrics::gauge!("sertus_flow_task_status", Outcome::Skipped.value(), &labels);
+        record_run(&labels, Outcome::Skipped);
+    }
+
+    /// check the schedule, checkers, and dependencies of tasks which must be known and acyclic
+    pub fn validate(&self) -> Result<()> {
+        match self.diagnose().into_iter().next() {
+            Some((_, e)) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// every problem found by validate, with the name of the task it is about
+    pub fn diagnose(&self) -> Vec<(Option<&str>, AppError)> {
+        let mut problems = vec![];
+        if let Err(e) = self.scheduler() {
+            problems.push((None, app_error!("Flow({}) {}", self.name, e)));
+        }
+        for task in self.tasks.iter() {
+            let task_error = |e| {
+                (
+                    Some(task.name.as_str()),
+                    app_error!("Flow({}) Task({}) {}", self.name, task.name, e),
+                )
+            };
+            if let Some(interval) = task.interval {
+                if let Err(e) = self.task_scheduler(interval) {
+                    problems.push(task_error(e));
                 }
             }
-            debug!("Ended Flow({})", self.name);
-            sleep(Duration::from_secs(self.interval)).await
+            if let Err(e) = task.checker.validate() {
+                problems.push(task_error(e));
+            }
+        }
+        let mut names = HashSet::new();
+        for task in self.tasks.iter() {
+            if !names.insert(task.name.as_str()) {
+                problems.push((
+                    Some(task.name.as_str()),
+                    app_error!("Flow({}) has duplicate Task({})", self.name, task.name),
+                ));
+            }
+        }
+        for task in self.tasks.iter() {
+            for dependency in task.depends_on.iter().flatten() {
+                if !names.contains(dependency.as_str()) {
+                    problems.push((
+                        Some(task.name.as_str()),
+                        app_error!(
+                            "Flow({}) Task({}) depends on unknown Task({})",
+                            self.name,
+                            task.name,
+                            dependency
+                        ),
+                    ));
+                }
+            }
+        }
+        // depth first search, a task on the path being visited again is a cycle
+        fn visit<'a>(
+            flow: &'a Flow,
+            name: &'a str,
+            path: &mut Vec<&'a str>,
+            done: &mut HashSet<&'a str>,
+        ) -> Result<()> {
+            if done.contains(name) {
+                return Ok(());
+            }
+            if let Some(start) = path.iter().position(|n| *n == name) {
+                let mut cycle = path[start..].to_vec();
+                cycle.push(name);
+                return Err(app_error!(
+                    "Flow({}) has a dependency cycle: {}",
+                    flow.name,
+                    cycle.join(" -> ")
+                ));
+            }
+            path.push(name);
+            if let Some(task) = flow.tasks.iter().find(|t| t.name == name) {
+                for dependency in task.depends_on.iter().flatten() {
+                    visit(flow, dependency, path, done)?;
+                }
+            }
+            path.pop();
+            done.insert(name);
+            Ok(())
+        }
+        let mut done = HashSet::new();
+        for task in self.tasks.iter() {
+            if let Err(e) = visit(self, &task.name, &mut vec![], &mut done) {
+                problems.push((Some(task.name.as_str()), e));
+                break;
+            }
+        }
+        problems
+    }
+
+    async fn exec_task(&self, task: &Task, labels: &[(String, String)]) -> Attempt {
+        let semaphore = GLOBAL_SEMAPHORE
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|(_, semaphore)| semaphore.clone());
+        let _permit = match semaphore {
+            Some(semaphore) => semaphore.acquire_owned().await.ok(),
+            None => None,
+        };
+        debug!("Running Task({}), {:?}", task.name, task.checker);
+        let task_timeout = task.timeout.or(self.timeout);
+        let start = Instant::now();
+        let result = match task_timeout {
+            Some(secs) => timeout(Duration::from_secs(secs), task.checker.exec()).await,
+            None => Ok(task.checker.exec().await),
+        };
+        metrics::histogram!(
+            "sertus_flow_task_duration_seconds",
+            start.elapsed().as_secs_f64(),
+            labels
+        );
+        match result {
+            Ok(Ok(result)) => Attempt::Done(result),
+            Ok(Err(e)) => Attempt::Error(e),
+            Err(_) => {
+                metrics::increment_counter!("sertus_flow_task_timeout_total", labels);
+                Attempt::Timeout(task_timeout.unwrap_or_default())
+            }
+        }
+    }
+
+    /// run a task with its retry policy,
+    /// only the final outcome is reported through the thresholds of the task,
+    /// returns the reported outcome
+    async fn run_task(&self, task: &Task, state: &mut TaskState) -> Outcome {
+        let mut labels: Vec<(String, String)> = vec![
+            ("flow".to_owned(), self.name.clone()),
+            ("task".to_owned(), task.name.clone()),
+        ];
+        let mut attempts = 0;
+        let attempt = loop {
+            attempts += 1;
+            let attempt = self.exec_task(task, &labels).await;
+            match &task.retry {
+                Some(retry) if retry.should_retry(attempt.outcome(), attempts) => {
+                    let delay = retry.delay(attempts);
+                    warn!(
+                        "Retrying Task({}) in {:?}, attempt {} was {:?}",
+                        task.name,
+                        delay,
+                        attempts,
+                        attempt.outcome()
+                    );
+                    sleep(delay).await;
+                }
+                _ => break attempt,
+            }
+        };
+        let outcome = attempt.outcome();
+        let (message, output) = (attempt.message(), attempt.output());
+        match attempt {
+            Attempt::Done(result) => {
+                // checker metrics are labeled with the flow and task, to be joined with
+                // the status and retired with the task
+                for metric in result.metrics.iter() {
+                    let metric = metric.clone().with_labels(&labels);
+                    crate::metrics::own(&metric.key());
+                    metric.send();
+                }
+                labels.extend(result.labels.iter().cloned());
+                crate::metrics::expose(&self.name, &task.name, result.families.clone());
+                debug!("metrics labels: {:?}", labels);
+                if result.is_ok() {
+                    debug!("{:?}, {:?}", task.checker, result);
+                    info!("Succeeded Task({})", task.name);
+                } else {
+                    warn!(
+                        "{:?}, {:?}: {}",
+                        task.checker, result.status, result.message
+                    );
+                    warn!("Failed Task({})", task.name);
+                }
+            }
+            Attempt::Error(e) => {
+                error!("Error Task({}), {}", task.name, e);
+            }
+            Attempt::Timeout(secs) => {
+                warn!("Timeout Task({}) after {}s", task.name, secs);
+            }
+        }
+        let previous = state.reported;
+        let last_reported = state.last_reported;
+        let was_flapping = state.flapping;
+        let reported = state.update(task, outcome, Instant::now());
+        if reported != outcome {
+            debug!(
+                "Task({}) {:?} is reported as {:?}, consecutive failures {}, successes {}",
+                task.name,
+                outcome,
+                reported,
+                state.consecutive_failures,
+                state.consecutive_successes
+            );
+        }
+        if state.flapping != was_flapping {
+            warn!("Task({}) flapping: {}", task.name, state.flapping);
+        } else if let Some(previous) = previous.filter(|p| Some(*p) != state.reported) {
+            info!(
+                "Task({}) changed from {:?} to {:?}",
+                task.name, previous, reported
+            );
+        }
+        let mut transition = Transition::new(&self.name, &task.name, last_reported, reported);
+        transition.message = message;
+        transition.output = output;
+        transition.labels = labels.iter().cloned().collect();
+        if let Some(since) = state.since {
+            transition.since = DateTime::<Utc>::from(since).to_rfc3339();
+        }
+        notifier::notify(transition);
+        metrics::gauge!("sertus_flow_task_status", reported.value(), &labels);
+        metrics::gauge!(
+            "sertus_flow_task_flapping",
+            state.flapping as u8 as f64,
+            &labels
+        );
+        metrics::gauge!("sertus_flow_task_attempts", attempts as f64, &labels);
+        metrics::gauge!(
+            "sertus_flow_task_consecutive_failures",
+            state.consecutive_failures as f64,
+            &labels
+        );
+        record_run(&labels, outcome);
+        reported
+    }
+}
+
+/// count a run by its outcome before thresholds, and the time of the last run and success
+fn record_run(labels: &[(String, String)], outcome: Outcome) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let mut run_labels = labels.to_vec();
+    run_labels.push(("outcome".to_owned(), outcome.label().to_owned()));
+    metrics::increment_counter!("sertus_flow_task_runs_total", &run_labels);
+    metrics::gauge!("sertus_flow_task_last_run_timestamp_seconds", now, labels);
+    if outcome == Outcome::Success {
+        metrics::gauge!(
+            "sertus_flow_task_last_success_timestamp_seconds",
+            now,
+            labels
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::checker::{process::ProcessChecker, script::ScriptChecker, Checker};
+
+    #[tokio::test]
+    async fn test_parallel_flow() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut script_file = NamedTempFile::new()?;
+        script_file.write_all(b"#!/bin/bash\nsleep 1\n")?;
+        let path = script_file.path().to_str().ok_or("path to str failed")?;
+
+        let mut flow = Flow::new("parallel");
+        for i in 0..4 {
+            flow.add_task(Task::new(
+                format!("task {}", i),
+                Checker::ScriptChecker(ScriptChecker::new(path)),
+            ));
         }
+        flow.mode = Some(Mode::Parallel);
+        flow.max_concurrency = Some(2);
+        let flow = Arc::new(flow);
+        let mut states = HashMap::new();
+        let start = Instant::now();
+        flow.run_tasks(flow.tasks.iter().collect(), &mut states, &flow.semaphore())
+            .await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(2) && elapsed < Duration::from_secs(3));
+        assert_eq!(states.len(), 4);
+        assert!(states
+            .values()
+            .all(|state| state.reported == Some(Outcome::Success)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flow_dependencies() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut fail_file = NamedTempFile::new()?;
+        fail_file.write_all(b"#!/bin/bash\nexit 1\n")?;
+        let fail = fail_file.path().to_str().ok_or("path to str failed")?;
+
+        let mut flow = Flow::new("dag");
+        let mut dependent = Task::new(
+            "dependent",
+            Checker::ProcessChecker(ProcessChecker::new("")),
+        );
+        dependent.depends_on = Some(vec!["network".to_string()]);
+        let mut transitive = Task::new(
+            "transitive",
+            Checker::ProcessChecker(ProcessChecker::new("")),
+        );
+        transitive.depends_on = Some(vec!["dependent".to_string()]);
+        flow.add_task(transitive)
+            .add_task(dependent)
+            .add_task(Task::new(
+                "network",
+                Checker::ScriptChecker(ScriptChecker::new(fail)),
+            ))
+            .add_task(Task::new(
+                "independent",
+                Checker::ProcessChecker(ProcessChecker::new("")),
+            ));
+        flow.validate()?;
+        let once = flow.clone().run_once(None).await?;
+        let names = once
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["transitive", "dependent", "network", "independent"]
+        );
+        // a selected task runs alone, its dependencies have never run
+        let once = flow.clone().run_once(Some("dependent")).await?;
+        assert_eq!(once, vec![("dependent".to_string(), Outcome::Success)]);
+        assert!(flow.clone().run_once(Some("unknown")).await.is_err());
+
+        let flow = Arc::new(flow);
+        let mut states = HashMap::new();
+        let outcomes = flow
+            .run_tasks(flow.tasks.iter().collect(), &mut states, &flow.semaphore())
+            .await;
+        assert_eq!(outcomes["network"], Outcome::Failure);
+        assert_eq!(outcomes["dependent"], Outcome::Skipped);
+        assert_eq!(outcomes["transitive"], Outcome::Skipped);
+        assert_eq!(outcomes["independent"], Outcome::Success);
+        assert_eq!(states["dependent"].last_reported, Some(Outcome::Skipped));
+
+        // a dependency out of the due tasks is judged by its last reported outcome
+        let outcomes = flow
+            .run_tasks(vec![&flow.tasks[1]], &mut states, &flow.semaphore())
+            .await;
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes["dependent"], Outcome::Skipped);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_task_interval() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let runs = dir.path().join("runs");
+        let fast = dir.path().join("fast.sh");
+        let slow = dir.path().join("slow.sh");
+        std::fs::write(&fast, format!("echo run >> {}\n", runs.display()))?;
+        std::fs::write(&slow, "sleep 3\n")?;
+
+        let mut flow = Flow::new("task interval");
+        flow.interval = 60;
+        flow.add_task(Task::new(
+            "slow",
+            Checker::ScriptChecker(ScriptChecker::new(slow.to_str().unwrap())),
+        ));
+        let mut task = Task::new(
+            "fast",
+            Checker::ScriptChecker(ScriptChecker::new(fast.to_str().unwrap())),
+        );
+        task.interval = Some(1);
+        flow.add_task(task);
+        let start = Instant::now();
+        flow.run_until(sleep(Duration::from_millis(2500))).await;
+        // the fast task keeps its interval while the slow one runs,
+        // which is finished before the flow stops
+        let runs = std::fs::read_to_string(runs)?.lines().count();
+        assert!(runs >= 2, "fast task ran {} times", runs);
+        assert!(start.elapsed() >= Duration::from_secs(3));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flow_concurrency() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let (lock, overlaps, runs) = (
+            dir.path().join("lock"),
+            dir.path().join("overlaps"),
+            dir.path().join("runs"),
+        );
+        let script = dir.path().join("check.sh");
+        std::fs::write(
+            &script,
+            format!(
+                "mkdir {0} || echo overlap >> {1}\necho run >> {2}\nsleep 0.5\nrmdir {0}\n",
+                lock.display(),
+                overlaps.display(),
+                runs.display()
+            ),
+        )?;
+
+        let mut flow = Flow::new("concurrency");
+        flow.mode = Some(Mode::Parallel);
+        flow.max_concurrency = Some(1);
+        for name in ["a", "b"] {
+            let mut task = Task::new(
+                name,
+                Checker::ScriptChecker(ScriptChecker::new(script.to_str().unwrap())),
+            );
+            task.interval = Some(1);
+            flow.add_task(task);
+        }
+        flow.run_until(sleep(Duration::from_millis(1500))).await;
+        // tasks with their own interval share the max concurrency of the flow
+        assert!(std::fs::read_to_string(runs)?.lines().count() >= 2);
+        assert!(!overlaps.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_task_metrics() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        crate::metrics::install_recorder(&crate::metrics::Metrics::default());
+        let mut script_file = NamedTempFile::new()?;
+        script_file.write_all(
+            b"echo '#metric task_metrics_test gauge {flow=own} 1'\necho '#metric task_metrics_total counter {k=v} 1'\nexit 1\n",
+        )?;
+        let path = script_file.path().to_str().ok_or("path to str failed")?;
+        let mut flow = Flow::new("task metrics");
+        flow.add_task(Task::new(
+            "script",
+            Checker::ScriptChecker(ScriptChecker::new(path)),
+        ));
+        let flow = Arc::new(flow);
+        let mut states = HashMap::new();
+        for _ in 0..2 {
+            flow.run_tasks(flow.tasks.iter().collect(), &mut states, &flow.semaphore())
+                .await;
+        }
+        let labels = r#"flow="task metrics",task="script""#;
+        let rendered = crate::metrics::render();
+        assert!(rendered.contains(&format!("sertus_flow_task_status{{{}}} 0", labels)));
+        assert!(rendered.contains(&format!(
+            "sertus_flow_task_runs_total{{{},outcome=\"failure\"}} 2",
+            labels
+        )));
+        assert!(rendered.contains(&format!(
+            "sertus_flow_task_consecutive_failures{{{}}} 2",
+            labels
+        )));
+        assert!(rendered.contains(&format!(
+            "sertus_flow_task_duration_seconds_bucket{{{},le=\"10\"}} 2",
+            labels
+        )));
+        assert!(rendered.contains(&format!(
+            "sertus_flow_task_last_run_timestamp_seconds{{{}}}",
+            labels
+        )));
+        assert!(!rendered.contains(&format!(
+            "sertus_flow_task_last_success_timestamp_seconds{{{}}}",
+            labels
+        )));
+        // checker metrics are labeled with the flow and task, and retired with them
+        assert!(rendered.contains(&format!(
+            "sertus_task_metrics_test{{{},exported_flow=\"own\"}} 1",
+            labels
+        )));
+        assert!(rendered.contains(&format!("task_metrics_total{{{},k=\"v\"}} 2", labels)));
+
+        std::fs::write(script_file.path(), "exit 0\n")?;
+        flow.run_tasks(flow.tasks.iter().collect(), &mut states, &flow.semaphore())
+            .await;
+        let rendered = crate::metrics::render();
+        assert!(rendered.contains(&format!(
+            "sertus_flow_task_consecutive_failures{{{}}} 0",
+            labels
+        )));
+        assert!(rendered.contains(&format!(
+            "sertus_flow_task_last_success_timestamp_seconds{{{}}}",
+            labels
+        )));
+        crate::metrics::retire("task metrics", Some("script"));
+        let rendered = crate::metrics::render();
+        assert!(!rendered.contains("task_metrics_test"));
+        assert!(!rendered.contains("task_metrics_total"));
+        crate::metrics::unretire("task metrics", &["script"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_flow_validate() {
+        let mut flow = Flow::new("dag");
+        for (name, depends_on) in [("a", "c"), ("b", "a"), ("c", "b")] {
+            let mut task = Task::new(name, Checker::ProcessChecker(ProcessChecker::new("")));
+            task.depends_on = Some(vec![depends_on.to_string()]);
+            flow.add_task(task);
+        }
+        let err = flow.validate().unwrap_err().to_string();
+        assert!(err.contains("a -> c -> b -> a"), "{}", err);
+
+        flow.tasks[0].depends_on = Some(vec!["x".to_string()]);
+        let err = flow.validate().unwrap_err().to_string();
+        assert!(err.contains("unknown Task(x)"), "{}", err);
     }
 }
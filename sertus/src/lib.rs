@@ -2,10 +2,14 @@
 
 pub mod checker;
 pub mod config;
+pub mod daemon;
 pub mod error;
 pub mod executor;
+pub mod exposition;
 pub mod flow;
 pub mod metric_ext;
 pub mod metrics;
+pub mod notifier;
 pub mod pkg;
+pub mod schedule;
 pub mod task;
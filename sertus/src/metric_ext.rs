@@ -5,11 +5,65 @@ use tracing::warn;
 
 use crate::error::Result;
 
-const LABEL_RE: Lazy<std::result::Result<Regex, regex::Error>> =
+static LABEL_RE: Lazy<std::result::Result<Regex, regex::Error>> =
     Lazy::new(|| Regex::new(r"#label \{([^}]+)\}"));
-const METRIC_RE: Lazy<std::result::Result<Regex, regex::Error>> =
+static METRIC_RE: Lazy<std::result::Result<Regex, regex::Error>> =
     Lazy::new(|| Regex::new(r"#metric (\w+)\s+(\w+) \{([^}]+)\}\s+(.+)"));
 
+/// split `k=v, x="a, b"` by commas out of double quotes
+fn split_label_items(labels: &str) -> Vec<&str> {
+    let mut items = vec![];
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in labels.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                items.push(&labels[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&labels[start..]);
+    items
+}
+
+/// label pairs of `k=v, x="a, b"`, a double quoted value may contain `,` `=` and escapes
+fn parse_labels(labels: &str) -> Vec<(String, String)> {
+    split_label_items(labels)
+        .into_iter()
+        .map(|item| {
+            let (key, value) = item.split_once('=').unwrap_or((item, ""));
+            let value = value.trim();
+            let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                Some(quoted) => unescape(quoted),
+                None => value.to_string(),
+            };
+            (key.trim().to_string(), value)
+        })
+        .collect()
+}
+
+/// unescape `\\`, `\"` and `\n` of a quoted label value
+pub(crate) fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
 pub trait LabelExtractor {
     fn extract_label(&self) -> Result<Vec<(String, String)>>;
 }
@@ -17,22 +71,12 @@ pub trait LabelExtractor {
 impl LabelExtractor for String {
     fn extract_label(&self) -> Result<Vec<(String, String)>> {
         let mut labels = vec![];
-        let label_re = LABEL_RE;
-        let re = label_re.as_ref().map_err(|e| e.to_owned())?;
+        let re = LABEL_RE.as_ref().map_err(|e| e.to_owned())?;
         self.lines().for_each(|line| {
             if line.starts_with("#label") {
                 let captures = re.captures(line);
                 if let Some(captures) = captures {
-                    let line_labels = captures[1]
-                        .split(',')
-                        .map(|item| {
-                            let mut iter = item.split('=');
-                            let key = iter.next().unwrap_or("").trim().to_string();
-                            let value = iter.next().unwrap_or("").trim().to_string();
-                            (key, value)
-                        })
-                        .collect::<Vec<(String, String)>>();
-                    labels.extend(line_labels);
+                    labels.extend(parse_labels(&captures[1]));
                 }
             }
         });
@@ -44,9 +88,12 @@ impl LabelExtractor for String {
 pub enum MetricValue {
     F64(f64),
     U64(u64),
+    /// observations of a histogram or summary
+    Observations(Vec<f64>),
 }
 /// #metric minio_bucket_usage_total_bytes gauge {bucket=x,server="node0.minio.com:9000"} 9.78413036153943e+14
 /// #metric minio_request_times counter {bucket=x,server="node0.minio.com:9000",type="get"} 1
+/// #metric minio_request_seconds histogram {bucket=x,type="get"} 0.12,0.3,0.05
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct MetricStruct {
     pub name: String,
@@ -60,6 +107,7 @@ impl From<MetricValue> for f64 {
         match value {
             MetricValue::F64(v) => v,
             MetricValue::U64(v) => v as f64,
+            MetricValue::Observations(v) => v.last().copied().unwrap_or_default(),
         }
     }
 }
@@ -69,20 +117,68 @@ impl From<MetricValue> for u64 {
         match value {
             MetricValue::U64(v) => v,
             MetricValue::F64(v) => v as u64,
+            MetricValue::Observations(v) => v.last().copied().unwrap_or_default() as u64,
         }
     }
 }
 impl MetricStruct {
+    pub fn gauge(name: impl Into<String>, labels: Vec<(String, String)>, value: f64) -> Self {
+        Self {
+            name: name.into(),
+            typ: "gauge".to_string(),
+            labels,
+            value: MetricValue::F64(value),
+        }
+    }
+
+    /// observed values, a single value for gauges and counters
+    pub fn observations(&self) -> Vec<f64> {
+        match &self.value {
+            MetricValue::Observations(v) => v.clone(),
+            value => vec![value.clone().into()],
+        }
+    }
+
+    /// the metric labeled with `labels` first, its own labels of the same keys are kept
+    /// as `exported_<key>`, e.g. the flow and task of a checker metric
+    pub fn with_labels(mut self, labels: &[(String, String)]) -> Self {
+        let own = std::mem::take(&mut self.labels);
+        self.labels = labels.to_vec();
+        for (key, value) in own {
+            let key = match labels.iter().any(|(k, _)| *k == key) {
+                true => format!("exported_{}", key),
+                false => key,
+            };
+            self.labels.push((key, value));
+        }
+        self
+    }
+
+    /// the name the metric is recorded with,
+    /// gauges and histograms are prefixed with `sertus_`, buckets are matched with it,
+    /// counters and summaries are not, as a summary would match the default buckets
+    pub fn key(&self) -> String {
+        match self.typ.as_str() {
+            "gauge" | "histogram" => format!("{}{}", "sertus_", self.name),
+            _ => self.name.clone(),
+        }
+    }
+
     pub fn send(&self) {
         match self.typ.as_str() {
             "gauge" => {
-                let k = format!("{}{}", "sertus_", self.name);
                 let v: f64 = self.value.clone().into();
-                metrics::gauge!(k, v, &self.labels);
+                metrics::gauge!(self.key(), v, &self.labels);
             }
             "counter" => {
                 let v: u64 = self.value.clone().into();
-                metrics::counter!(self.name.clone(), v, &self.labels);
+                metrics::counter!(self.key(), v, &self.labels);
+            }
+            "histogram" | "summary" => {
+                let k = self.key();
+                for v in self.observations() {
+                    metrics::histogram!(k.clone(), v, &self.labels);
+                }
             }
             _ => {
                 warn!("unknown metric type: {}", self.typ);
@@ -98,23 +194,14 @@ pub trait MetricExtractor {
 impl MetricExtractor for String {
     fn extract_metric(&self) -> Result<Vec<MetricStruct>> {
         let mut metrics = vec![];
-        let metric_re = METRIC_RE;
-        let re = metric_re.as_ref().map_err(|e| e.to_owned())?;
+        let re = METRIC_RE.as_ref().map_err(|e| e.to_owned())?;
         self.lines().for_each(|line| {
             if line.starts_with("#metric") {
                 let captures = re.captures(line);
                 if let Some(captures) = captures {
                     let name = captures[1].to_string();
                     let typ = captures[2].to_string();
-                    let labels = captures[3]
-                        .split(',')
-                        .map(|item| {
-                            let mut iter = item.split('=');
-                            let key = iter.next().unwrap_or("").trim().to_string();
-                            let value = iter.next().unwrap_or("").trim().to_string();
-                            (key, value)
-                        })
-                        .collect::<Vec<(String, String)>>();
+                    let labels = parse_labels(&captures[3]);
 
                     let value = match typ.as_str() {
                         "gauge" => {
@@ -125,6 +212,13 @@ impl MetricExtractor for String {
                             let value = captures[4].parse::<u64>().unwrap_or(0);
                             MetricValue::U64(value)
                         }
+                        "histogram" | "summary" => {
+                            let values = captures[4]
+                                .split(',')
+                                .filter_map(|v| v.trim().parse::<f64>().ok())
+                                .collect();
+                            MetricValue::Observations(values)
+                        }
                         _ => {
                             warn!("unknown metric type: {}", typ);
                             return;
@@ -144,6 +238,81 @@ impl MetricExtractor for String {
     }
 }
 
+/// A line which looks like a `#label` or `#metric` directive but can not be extracted
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidDirective {
+    /// 1-based line number in the output
+    pub line_number: usize,
+    pub line: String,
+    pub reason: String,
+}
+
+/// find directive lines of an output which are dropped or partly extracted, and why
+pub fn invalid_directives(output: &str) -> Result<Vec<InvalidDirective>> {
+    let label_re = LABEL_RE.as_ref().map_err(|e| e.to_owned())?;
+    let metric_re = METRIC_RE.as_ref().map_err(|e| e.to_owned())?;
+    let mut invalid = vec![];
+    for (index, line) in output.lines().enumerate() {
+        let reason = if line.starts_with("#label") {
+            match label_re.captures(line) {
+                Some(captures) => invalid_labels(&captures[1]),
+                None => Some("expected `#label {key=value, ...}`".to_string()),
+            }
+        } else if line.starts_with("#metric") {
+            match metric_re.captures(line) {
+                Some(captures) => invalid_labels(&captures[3]).or_else(|| {
+                    let value = captures[4].trim();
+                    match &captures[2] {
+                        "gauge" if value.parse::<f64>().is_err() => {
+                            Some(format!("gauge value {:?} is not a float, 0 is used", value))
+                        }
+                        "counter" if value.parse::<u64>().is_err() => Some(format!(
+                            "counter value {:?} is not an unsigned integer, 0 is used",
+                            value
+                        )),
+                        "histogram" | "summary" => value
+                            .split(',')
+                            .find(|v| v.trim().parse::<f64>().is_err())
+                            .map(|v| {
+                                format!("observation {:?} is not a float, it is skipped", v.trim())
+                            }),
+                        "gauge" | "counter" => None,
+                        typ => Some(format!("unknown metric type {:?}", typ)),
+                    }
+                }),
+                None => {
+                    Some("expected `#metric <name> <type> {key=value, ...} <value>`".to_string())
+                }
+            }
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            invalid.push(InvalidDirective {
+                line_number: index + 1,
+                line: line.to_string(),
+                reason,
+            });
+        }
+    }
+    Ok(invalid)
+}
+
+fn invalid_labels(labels: &str) -> Option<String> {
+    split_label_items(labels)
+        .into_iter()
+        .find_map(|item| match item.split_once('=') {
+            Some((key, _)) if key.trim().is_empty() => {
+                Some(format!("label {:?} has no key", item.trim()))
+            }
+            Some(_) => None,
+            None => Some(format!(
+                "label {:?} has no '=', the value is empty",
+                item.trim()
+            )),
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -160,6 +329,20 @@ mod tests {
             ]
         );
     }
+    #[test]
+    fn test_extract_quoted_label() {
+        let s = r#"#metric xxx gauge {k="a, b=c", x=y, q="say \"hi\""} 1.0"#.to_string();
+        let metrics = s.extract_metric().unwrap();
+        assert_eq!(
+            metrics[0].labels,
+            vec![
+                ("k".to_string(), "a, b=c".to_string()),
+                ("x".to_string(), "y".to_string()),
+                ("q".to_string(), "say \"hi\"".to_string())
+            ]
+        );
+    }
+
     #[test]
     fn test_extract_metric() {
         let s = r#"
@@ -189,4 +372,33 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_extract_observations() {
+        let s =
+            "#metric latency histogram {k=v} 0.1, 0.25,x\n#metric size summary {k=v} 3".to_string();
+        let metrics = s.extract_metric().unwrap();
+        assert_eq!(metrics[0].value, MetricValue::Observations(vec![0.1, 0.25]));
+        assert_eq!(metrics[1].typ, "summary");
+        assert_eq!(metrics[1].observations(), vec![3.0]);
+    }
+
+    #[test]
+    fn test_invalid_directives() {
+        let s = r#"#label {k=v}
+#label k=v
+#label {k}
+#metric xxx gauge {k=v} abc
+#metric xxx histogram {k=v} 1,x
+#metric xxx counter {k=v} -1
+#metric xxx gauge 1.0
+#metric xxx gauge {k=v} 1.0
+#metric xxx meter {k=v} 1
+"#;
+        let invalid = invalid_directives(s).unwrap();
+        let lines = invalid.iter().map(|i| i.line_number).collect::<Vec<_>>();
+        assert_eq!(lines, vec![2, 3, 4, 5, 6, 7, 9]);
+        assert!(invalid[3].reason.contains("observation \"x\""));
+        assert!(invalid[6].reason.contains("unknown metric type"));
+    }
 }
@@ -1,14 +1,39 @@
-use std::{future::ready, net::SocketAddr, time::Duration};
+use std::{
+    collections::{BTreeMap, HashSet},
+    future::ready,
+    net::SocketAddr,
+    sync::RwLock,
+    time::Duration,
+};
 
 use axum::{routing::get, Router};
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
 use metrics_util::MetricKindMask;
+use once_cell::sync::{Lazy, OnceCell};
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+use crate::{
+    app_error,
+    error::Result,
+    exposition::{parse_sample, render as render_families, MetricFamily},
+};
 
 const METRICS_ROUTE_PATH: &str = "/metrics";
 const METRICS_BUCKET: &str = "sertus";
 
+static RECORDER_HANDLE: OnceCell<(RecorderSettings, PrometheusHandle)> = OnceCell::new();
+/// (flow, task) of removed flows and tasks, task none means the whole flow
+static RETIRED: Lazy<RwLock<HashSet<Retired>>> = Lazy::new(|| RwLock::new(HashSet::new()));
+type Retired = (String, Option<String>);
+/// families recorded by checkers of tasks without the `sertus_` prefix, e.g. counters
+static OWNED: Lazy<RwLock<HashSet<String>>> = Lazy::new(|| RwLock::new(HashSet::new()));
+pub type Buckets = BTreeMap<String, Vec<f64>>;
+type Exposed = BTreeMap<(String, String), Vec<MetricFamily>>;
+/// families re-exported from the output of checkers by (flow, task)
+static EXPOSED: Lazy<RwLock<Exposed>> = Lazy::new(|| RwLock::new(BTreeMap::new()));
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Metrics {
     PushGateway(PushGateway),
@@ -25,6 +50,8 @@ impl Default for Metrics {
 pub struct Server {
     pub addr: String,
     pub bucket: Option<String>,
+    /// Histogram buckets by metric name without the `sertus_` prefix
+    pub buckets: Option<Buckets>,
 }
 
 impl Default for Server {
@@ -32,6 +59,7 @@ impl Default for Server {
         Server {
             addr: "127.0.0.1:9296".to_string(),
             bucket: None,
+            buckets: None,
         }
     }
 }
@@ -44,6 +72,20 @@ pub struct PushGateway {
     pub interval: Option<u64>,
     /// Duration of metrics record retention, default 60s
     pub idle_timeout: Option<u64>,
+    /// What to do with the grouping key on shutdown, default push
+    pub on_shutdown: Option<OnShutdown>,
+    /// Histogram buckets by metric name without the `sertus_` prefix
+    pub buckets: Option<Buckets>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OnShutdown {
+    /// push the metrics a last time
+    #[default]
+    Push,
+    /// delete the metrics of the grouping key
+    Delete,
 }
 
 impl Default for PushGateway {
@@ -52,51 +94,463 @@ impl Default for PushGateway {
             endpoint: "http://127.0.0.1:9091/metrics/job/sertus/instance/127.0.0.1".to_string(),
             interval: Some(10),
             idle_timeout: Some(60),
+            on_shutdown: None,
+            buckets: None,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn buckets(&self) -> Option<&Buckets> {
+        match self {
+            Metrics::Server(s) => s.buckets.as_ref(),
+            Metrics::PushGateway(p) => p.buckets.as_ref(),
+        }
+    }
+
+    /// check the server addr or the push gateway endpoint, and the histogram buckets
+    pub fn validate(&self) -> Result<()> {
+        for (name, buckets) in self.buckets().into_iter().flatten() {
+            if buckets.is_empty() {
+                return Err(app_error!("buckets of metric {:?} are empty", name));
+            }
+            if buckets.iter().any(|b| !b.is_finite()) || buckets.windows(2).any(|w| w[0] >= w[1]) {
+                return Err(app_error!(
+                    "buckets of metric {:?} must be finite and increasing: {:?}",
+                    name,
+                    buckets
+                ));
+            }
+        }
+        match self {
+            Metrics::Server(s) => s
+                .addr
+                .parse::<SocketAddr>()
+                .map(|_| ())
+                .map_err(|e| app_error!("invalid metrics addr {:?}: {}", s.addr, e)),
+            Metrics::PushGateway(p) => match reqwest::Url::parse(&p.endpoint) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+                Ok(url) => Err(app_error!(
+                    "invalid push gateway endpoint {:?}: unsupported scheme {}",
+                    p.endpoint,
+                    url.scheme()
+                )),
+                Err(e) => Err(app_error!(
+                    "invalid push gateway endpoint {:?}: {}",
+                    p.endpoint,
+                    e
+                )),
+            },
         }
     }
 }
 
+/// The settings the global recorder is built with, which can not change once it is installed
+#[derive(Debug, Clone, PartialEq)]
+struct RecorderSettings {
+    bucket: String,
+    buckets: Buckets,
+    idle_timeout: Option<Duration>,
+}
+
+impl From<&Metrics> for RecorderSettings {
+    fn from(config: &Metrics) -> Self {
+        let (bucket, idle_timeout) = match config {
+            Metrics::Server(s) => (s.bucket.clone(), None),
+            Metrics::PushGateway(p) => (
+                None,
+                Some(Duration::from_secs(p.idle_timeout.unwrap_or(60))),
+            ),
+        };
+        RecorderSettings {
+            bucket: bucket.unwrap_or(METRICS_BUCKET.to_string()),
+            buckets: config.buckets().cloned().unwrap_or_default(),
+            idle_timeout,
+        }
+    }
+}
+
+/// Install the global recorder once, the bucket, buckets and idle timeout are taken from the
+/// first config, a reload which changes them keeps the installed recorder with a warning
+pub fn install_recorder(config: &Metrics) -> &'static PrometheusHandle {
+    let settings = RecorderSettings::from(config);
+    let (installed, handle) = RECORDER_HANDLE.get_or_init(|| {
+        let handle =
+            setup_metrics_recorder(&settings.bucket, &settings.buckets, settings.idle_timeout);
+        (settings.clone(), handle)
+    });
+    if *installed != settings {
+        warn!(
+            "Metrics bucket, buckets and idle_timeout can not be changed by a reload, \
+             restart the daemon to apply them, keep {:?}",
+            installed
+        );
+    }
+    handle
+}
+
+/// Render metrics without the series of retired flows and tasks
+pub fn render() -> String {
+    let Some((_, handle)) = RECORDER_HANDLE.get() else {
+        return String::new();
+    };
+    let rendered = handle.render() + &render_exposed();
+    let retired = RETIRED.read().unwrap();
+    if retired.is_empty() {
+        return rendered;
+    }
+    let owned = OWNED.read().unwrap();
+    let mut output = String::new();
+    // the family of the following samples, and its comments
+    // which are written with its first sample left
+    let (mut family, mut comments) = (String::new(), String::new());
+    for line in rendered.lines() {
+        if let Some(comment) = line.strip_prefix("# ") {
+            let name = comment.split_whitespace().nth(1).unwrap_or_default();
+            if name != family {
+                family = name.to_string();
+                comments.clear();
+            }
+            comments.push_str(line);
+            comments.push('\n');
+            continue;
+        }
+        // separators of retired families are dropped with them
+        if line.is_empty() && !comments.is_empty() {
+            continue;
+        }
+        // lines which do not parse are kept, series sertus does not record for tasks as well
+        let retired = match parse_sample(line) {
+            Ok(sample) if family.starts_with("sertus_") || owned.contains(&family) => {
+                let label = |key: &str| {
+                    sample
+                        .labels
+                        .iter()
+                        .find(|(k, _)| k == key)
+                        .map(|(_, v)| v.as_str())
+                };
+                let (flow, task) = (label("flow"), label("task"));
+                retired.iter().any(|(f, t)| {
+                    flow == Some(f.as_str()) && t.as_deref().map_or(true, |t| task == Some(t))
+                })
+            }
+            _ => false,
+        };
+        if !retired {
+            output.push_str(&std::mem::take(&mut comments));
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+    output
+}
+
+/// Record that a family is recorded by checkers of tasks, so its series are retired with them,
+/// families prefixed with `sertus_` are recorded by sertus anyway
+pub fn own(name: &str) {
+    if name.starts_with("sertus_") || OWNED.read().unwrap().contains(name) {
+        return;
+    }
+    OWNED.write().unwrap().insert(name.to_string());
+}
+
+/// Replace the families re-exported for a task, e.g. parsed from the Prometheus output of a script
+pub fn expose(flow: &str, task: &str, families: Vec<MetricFamily>) {
+    let mut exposed = EXPOSED.write().unwrap();
+    let key = (flow.to_string(), task.to_string());
+    match families.is_empty() {
+        true => exposed.remove(&key),
+        false => exposed.insert(key, families),
+    };
+}
+
+fn render_exposed() -> String {
+    let exposed = EXPOSED.read().unwrap();
+    render_families(exposed.iter().flat_map(|((flow, task), families)| {
+        families.iter().map(move |family| {
+            (
+                family,
+                vec![
+                    ("flow".to_string(), flow.clone()),
+                    ("task".to_string(), task.clone()),
+                ],
+            )
+        })
+    }))
+}
+
+/// Stop rendering the series of a removed flow, or a removed task when `task` is some
+pub fn retire(flow: &str, task: Option<&str>) {
+    EXPOSED
+        .write()
+        .unwrap()
+        .retain(|(f, t), _| f != flow || task.map_or(false, |task| t != task));
+    RETIRED
+        .write()
+        .unwrap()
+        .insert((flow.to_string(), task.map(|t| t.to_string())));
+}
+
+/// Render the series of a flow and its tasks again, e.g. the flow is added back
+pub fn unretire(flow: &str, tasks: &[&str]) {
+    RETIRED
+        .write()
+        .unwrap()
+        .retain(|(f, t)| f != flow || t.as_ref().map_or(false, |t| !tasks.contains(&t.as_str())));
+}
+
 pub async fn start_metrics_server(config: Server) {
-    let app = metrics_app(config.bucket.unwrap_or(METRICS_BUCKET.to_string()));
-    let addr = config.addr.parse::<SocketAddr>().unwrap();
+    let addr = match config.addr.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("Invalid metrics addr {}: {}", config.addr, e);
+            return;
+        }
+    };
     info!("Metrics listening on {}", addr);
     info!("Metrics API: http://{}{}", addr, METRICS_ROUTE_PATH);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap()
+    match axum::Server::try_bind(&addr) {
+        Ok(server) => {
+            if let Err(e) = server.serve(metrics_app().into_make_service()).await {
+                error!("Metrics server on {}: {}", addr, e);
+            }
+        }
+        Err(e) => error!("Bind metrics server on {}: {}", addr, e),
+    }
 }
-fn metrics_app(metrics_bucket: impl Into<String>) -> Router {
-    let recorder_handle = setup_metrics_recorder(metrics_bucket);
-    Router::new().route(
-        METRICS_ROUTE_PATH,
-        get(move || ready(recorder_handle.render())),
-    )
+fn metrics_app() -> Router {
+    Router::new().route(METRICS_ROUTE_PATH, get(|| ready(render())))
 }
-fn setup_metrics_recorder(metrics_bucket: impl Into<String>) -> PrometheusHandle {
+fn setup_metrics_recorder(
+    metrics_bucket: &str,
+    buckets: &Buckets,
+    idle_timeout: Option<Duration>,
+) -> PrometheusHandle {
+    recorder_builder(metrics_bucket, buckets)
+        .idle_timeout(MetricKindMask::ALL, idle_timeout)
+        .install_recorder()
+        .unwrap()
+}
+
+fn recorder_builder(metrics_bucket: impl Into<String>, buckets: &Buckets) -> PrometheusBuilder {
     const EXPONENTIAL_SECONDS: &[f64] = &[
         0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
     ];
 
-    PrometheusBuilder::new()
+    // full names are matched before the prefix
+    buckets
+        .iter()
+        .fold(PrometheusBuilder::new(), |builder, (name, buckets)| {
+            builder
+                .set_buckets_for_metric(Matcher::Full(format!("sertus_{}", name)), buckets)
+                .unwrap()
+        })
         .set_buckets_for_metric(Matcher::Prefix(metrics_bucket.into()), EXPONENTIAL_SECONDS)
         .unwrap()
-        .install_recorder()
-        .unwrap()
 }
 
 pub async fn setup_pushgateway(config: PushGateway) {
     info!("Pushing metrics to {}", config.endpoint);
-    PrometheusBuilder::new()
-        .with_push_gateway(
-            config.endpoint,
-            Duration::from_secs(config.interval.unwrap_or(10)),
-        )
-        .expect("push gateway endpoint should be valid")
-        .idle_timeout(
-            MetricKindMask::ALL,
-            Some(Duration::from_secs(config.idle_timeout.unwrap_or(60))),
-        )
-        .install()
-        .expect("failed to install Prometheus recorder");
+    let client = reqwest::Client::new();
+    let interval = Duration::from_secs(config.interval.unwrap_or(10));
+    loop {
+        sleep(interval).await;
+        if let Err(e) = push(&client, &config.endpoint).await {
+            error!("{}", e);
+        }
+    }
+}
+
+/// push the metrics a last time, or delete the grouping key, according to the config
+pub async fn shutdown_pushgateway(config: &PushGateway) -> Result<()> {
+    let client = reqwest::Client::new();
+    match config.on_shutdown.unwrap_or_default() {
+        OnShutdown::Push => {
+            info!("Pushing final metrics to {}", config.endpoint);
+            push(&client, &config.endpoint).await
+        }
+        OnShutdown::Delete => {
+            info!("Deleting metrics of {}", config.endpoint);
+            let response = client
+                .delete(&config.endpoint)
+                .send()
+                .await
+                .map_err(|e| app_error!("Delete metrics of {}: {}", config.endpoint, e))?;
+            check_response(&config.endpoint, response).await
+        }
+    }
+}
+
+async fn push(client: &reqwest::Client, endpoint: &str) -> Result<()> {
+    let response = client
+        .put(endpoint)
+        .body(render())
+        .send()
+        .await
+        .map_err(|e| app_error!("Push metrics to {}: {}", endpoint, e))?;
+    check_response(endpoint, response).await
+}
+
+async fn check_response(endpoint: &str, response: reqwest::Response) -> Result<()> {
+    if response.status().is_success() {
+        return Ok(());
+    }
+    Err(app_error!(
+        "Push metrics to {}: unexpected status {}, {}",
+        endpoint,
+        response.status(),
+        response.text().await.unwrap_or_default()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{http::Method, routing::any};
+
+    use super::*;
+
+    #[test]
+    fn test_retire() {
+        let handle = install_recorder(&Metrics::default());
+        metrics::gauge!("sertus_retire_test", 1.0, "flow" => "retire_f1", "task" => "t1");
+        metrics::gauge!("sertus_retire_test", 1.0, "flow" => "retire_f1", "task" => "t2");
+        metrics::gauge!("sertus_retire_test", 1.0, "flow" => "retire_f2", "task" => "t1");
+        assert_eq!(handle.render().matches("sertus_retire_test{").count(), 3);
+
+        retire("retire_f1", Some("t1"));
+        retire("retire_f2", None);
+        let rendered = render();
+        assert_eq!(rendered.matches("sertus_retire_test{").count(), 1);
+        assert!(rendered.contains(r#"flow="retire_f1",task="t2""#));
+
+        // only the flow and task labels are compared, exactly
+        metrics::gauge!("sertus_retire_test", 1.0, "flow" => "retire_f3", "exported_flow" => "retire_f2", "task" => "retire_f2 t1");
+        metrics::gauge!("sertus_retire_test", 1.0, "flow" => "retire_f1 x", "task" => "t1");
+        assert_eq!(render().matches("sertus_retire_test{").count(), 3);
+
+        unretire("retire_f2", &["t1"]);
+        assert_eq!(render().matches("sertus_retire_test{").count(), 4);
+
+        // a family which is not recorded for tasks is kept, an owned one is retired
+        // with its comments
+        metrics::counter!("retire_other", 1, "flow" => "retire_f4", "task" => "t1");
+        metrics::counter!("retire_owned", 1, "flow" => "retire_f4", "task" => "t1");
+        own("retire_owned");
+        metrics::gauge!("sertus_retire_gone", 1.0, "flow" => "retire_f4", "task" => "t1");
+        retire("retire_f4", None);
+        let rendered = render();
+        assert!(rendered.contains(r#"retire_other{flow="retire_f4",task="t1"} 1"#));
+        assert!(!rendered.contains("retire_owned"));
+        assert!(!rendered.contains("sertus_retire_gone"));
+        assert!(rendered.contains("# TYPE sertus_retire_test gauge\n"));
+    }
+
+    #[test]
+    fn test_expose() -> Result<()> {
+        install_recorder(&Metrics::default());
+        let families =
+            crate::exposition::parse("# TYPE expose_test gauge\nexpose_test{k=\"v\"} 1\n")?;
+        expose("expose_f1", "t1", families.clone());
+        expose("expose_f1", "t2", families);
+        let rendered = render();
+        assert_eq!(rendered.matches("# TYPE expose_test gauge").count(), 1);
+        assert!(rendered.contains(r#"expose_test{flow="expose_f1",task="t2",k="v"} 1"#));
+
+        expose("expose_f1", "t2", vec![]);
+        assert_eq!(render().matches("expose_test{").count(), 1);
+        retire("expose_f1", None);
+        assert!(!render().contains("expose_test{"));
+        unretire("expose_f1", &[]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_recorder_buckets() {
+        use metrics::{Key, Recorder};
+
+        let buckets = Buckets::from([("latency".to_string(), vec![0.1, 1.0])]);
+        let recorder = recorder_builder(METRICS_BUCKET, &buckets).build_recorder();
+        for name in ["sertus_latency", "sertus_other", "latency"] {
+            recorder
+                .register_histogram(&Key::from_name(name))
+                .record(0.5);
+        }
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains("sertus_latency_bucket{le=\"1\"} 1"));
+        assert!(!rendered.contains("sertus_latency_bucket{le=\"0.25\"}"));
+        assert!(rendered.contains("sertus_other_bucket{le=\"0.25\"} 0"));
+        assert!(rendered.contains("latency{quantile=\"0.5\"}"));
+    }
+
+    #[test]
+    fn test_recorder_settings() {
+        let settings = |metrics: Metrics| RecorderSettings::from(&metrics);
+        let server = Server::default();
+        // a new addr restarts the exporter only
+        assert_eq!(
+            settings(Metrics::Server(server.clone())),
+            settings(Metrics::Server(Server {
+                addr: "127.0.0.1:9297".to_string(),
+                ..server.clone()
+            }))
+        );
+        for changed in [
+            Metrics::Server(Server {
+                bucket: Some("other".to_string()),
+                ..server.clone()
+            }),
+            Metrics::Server(Server {
+                buckets: Some(Buckets::from([("latency".to_string(), vec![0.1])])),
+                ..server.clone()
+            }),
+            Metrics::PushGateway(PushGateway::default()),
+        ] {
+            assert_ne!(settings(Metrics::Server(server.clone())), settings(changed));
+        }
+    }
+
+    #[test]
+    fn test_validate_buckets() {
+        let mut server = Server {
+            buckets: Some(Buckets::from([("latency".to_string(), vec![0.1, 1.0])])),
+            ..Default::default()
+        };
+        assert!(Metrics::Server(server.clone()).validate().is_ok());
+        for invalid in [vec![], vec![1.0, 0.1], vec![0.1, f64::INFINITY]] {
+            server.buckets = Some(Buckets::from([("latency".to_string(), invalid)]));
+            assert!(Metrics::Server(server.clone()).validate().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_pushgateway() -> Result<()> {
+        let methods = Arc::new(Mutex::new(vec![]));
+        let recorded = methods.clone();
+        let app = Router::new().route(
+            "/metrics/job/sertus",
+            any(move |method: Method| {
+                recorded.lock().unwrap().push(method);
+                ready("")
+            }),
+        );
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap());
+        let server = server.serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let mut config = PushGateway {
+            endpoint: format!("http://{}/metrics/job/sertus", addr),
+            ..Default::default()
+        };
+        shutdown_pushgateway(&config).await?;
+        config.on_shutdown = Some(OnShutdown::Delete);
+        shutdown_pushgateway(&config).await?;
+        assert_eq!(*methods.lock().unwrap(), vec![Method::PUT, Method::DELETE]);
+
+        config.endpoint = format!("http://{}/unknown", addr);
+        assert!(shutdown_pushgateway(&config).await.is_err());
+        Ok(())
+    }
 }
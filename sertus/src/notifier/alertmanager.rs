@@ -0,0 +1,263 @@
+use std::{collections::BTreeMap, fmt::Display, time::Duration};
+
+use chrono::{DateTime, Utc};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::Transition;
+use crate::{app_error, error::Result};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Alertmanager {
+    /// Base url like http://127.0.0.1:9093
+    pub url: String,
+    /// alertname label, default SertusTaskFailing
+    pub alertname: Option<String>,
+    /// Labels added to every alert
+    pub labels: Option<BTreeMap<String, String>>,
+    /// Label alerts with the `#label` labels of the checker, default true,
+    /// false sends them as annotations, for labels which change between runs
+    pub checker_labels: Option<bool>,
+    /// Interval(s) to re-send the alert of a failing task, default 60
+    pub resend_interval: Option<u64>,
+    /// Request timeout, default 10(s)
+    pub timeout: Option<u64>,
+}
+
+impl Alertmanager {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            alertname: None,
+            labels: None,
+            checker_labels: None,
+            resend_interval: None,
+            timeout: None,
+        }
+    }
+
+    pub fn resend_interval(&self) -> Duration {
+        Duration::from_secs(self.resend_interval.unwrap_or(60))
+    }
+
+    fn endpoint(&self) -> Result<Url> {
+        let url = format!("{}/api/v2/alerts", self.url.trim_end_matches('/'));
+        Url::parse(&url).map_err(|e| app_error!("invalid alertmanager url {:?}: {}", self.url, e))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let url = self.endpoint()?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(app_error!(
+                "invalid alertmanager url {:?}: unsupported scheme {}",
+                self.url,
+                url.scheme()
+            ));
+        }
+        if self.resend_interval == Some(0) {
+            return Err(app_error!("resend_interval must be greater than 0"));
+        }
+        Ok(())
+    }
+
+    /// the alert of a task, labeled by the flow, task and checker labels,
+    /// the checker labels are annotations with `checker_labels = false`,
+    /// a failing alert ends after 4 re-send intervals unless it is re-sent,
+    /// a recovered alert ends now
+    pub fn alert(&self, transition: &Transition) -> serde_json::Value {
+        let (mut labels, checker_annotations) = match self.checker_labels.unwrap_or(true) {
+            true => (transition.labels.clone(), BTreeMap::new()),
+            false => (BTreeMap::new(), transition.labels.clone()),
+        };
+        labels.insert("flow".to_string(), transition.flow.clone());
+        labels.insert("task".to_string(), transition.task.clone());
+        labels.extend(self.labels.clone().unwrap_or_default());
+        labels.insert(
+            "alertname".to_string(),
+            self.alertname
+                .clone()
+                .unwrap_or("SertusTaskFailing".to_string()),
+        );
+        let time = DateTime::parse_from_rfc3339(&transition.time)
+            .map(|time| time.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now());
+        let (starts_at, ends_at) = match transition.failing() {
+            true => (
+                transition.since.clone(),
+                time + chrono::Duration::from_std(self.resend_interval() * 4).unwrap_or_default(),
+            ),
+            // the failure began when the previous state began, which is unknown here,
+            // Alertmanager keeps the start of a known alert
+            false => (transition.time.clone(), time),
+        };
+        let mut annotations = checker_annotations;
+        annotations.insert(
+            "summary".to_string(),
+            format!(
+                "Flow({}) Task({}) is {}",
+                transition.flow,
+                transition.task,
+                transition.new_state.label()
+            ),
+        );
+        annotations.insert("description".to_string(), transition.message.clone());
+        annotations.insert(
+            "state".to_string(),
+            transition.new_state.label().to_string(),
+        );
+        json!({
+            "labels": labels,
+            "annotations": annotations,
+            "startsAt": starts_at,
+            "endsAt": ends_at.to_rfc3339(),
+        })
+    }
+
+    pub async fn send(&self, transition: &Transition) -> Result<()> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(self.timeout.unwrap_or(10)))
+            .build()?;
+        let response = client
+            .post(self.endpoint()?)
+            .json(&[self.alert(transition)])
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(app_error!(
+                "alertmanager {} responded {}: {}",
+                self.url,
+                response.status(),
+                response.text().await.unwrap_or_default()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Display for Alertmanager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use axum::{routing::post, Json, Router};
+
+    use super::*;
+    use crate::{
+        notifier::{dispatch, Dispatcher, Notifier, Target},
+        task::Outcome,
+    };
+
+    #[tokio::test]
+    async fn test_alertmanager() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let alerts = Arc::new(Mutex::new(vec![]));
+        let app = Router::new().route(
+            "/api/v2/alerts",
+            post({
+                let alerts = alerts.clone();
+                move |Json(body): Json<Vec<serde_json::Value>>| {
+                    alerts.lock().unwrap().extend(body);
+                    async {}
+                }
+            }),
+        );
+        let server = axum::Server::bind(&"127.0.0.1:0".parse()?).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let mut alertmanager = Alertmanager::new(format!("http://{}/", addr));
+        alertmanager.labels = Some(BTreeMap::from([(
+            "severity".to_string(),
+            "page".to_string(),
+        )]));
+        alertmanager.validate()?;
+        let dispatcher = Arc::new(Dispatcher::new(Notifier {
+            name: "alertmanager".to_string(),
+            target: Target::Alertmanager(alertmanager),
+            max_attempts: None,
+            backoff: None,
+            rate_limit: None,
+        }));
+        let notify = |transition| dispatch(&dispatcher, &transition, Instant::now());
+        let transition = |old_state, new_state| {
+            let mut transition = Transition::new("am", "disk", old_state, new_state);
+            transition.labels = BTreeMap::from([("mount".to_string(), "/".to_string())]);
+            transition.message = "95% used".to_string();
+            transition
+        };
+        // a success from the start, the first failure, a re-send within the interval,
+        // and the recovery
+        notify(transition(None, Outcome::Success));
+        notify(transition(Some(Outcome::Success), Outcome::Failure));
+        notify(transition(Some(Outcome::Failure), Outcome::Failure));
+        notify(transition(Some(Outcome::Failure), Outcome::Success));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let alerts = alerts.lock().unwrap().clone();
+        assert_eq!(alerts.len(), 2, "{:?}", alerts);
+        let (firing, resolved) = match alerts[0]["annotations"]["state"] == "failure" {
+            true => (&alerts[0], &alerts[1]),
+            false => (&alerts[1], &alerts[0]),
+        };
+        assert_eq!(
+            firing["labels"],
+            json!({
+                "alertname": "SertusTaskFailing",
+                "flow": "am",
+                "task": "disk",
+                "mount": "/",
+                "severity": "page"
+            })
+        );
+        assert_eq!(firing["annotations"]["description"], "95% used");
+        let ends_at = |alert: &serde_json::Value| {
+            DateTime::parse_from_rfc3339(alert["endsAt"].as_str().unwrap_or_default())
+        };
+        assert!(ends_at(firing)? > Utc::now() + chrono::Duration::seconds(200));
+        assert!(ends_at(resolved)? <= Utc::now());
+        assert_eq!(resolved["labels"], firing["labels"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_checker_annotations() {
+        let mut alertmanager = Alertmanager::new("http://127.0.0.1:9093");
+        let mut transition = Transition::new("am", "disk", None, Outcome::Failure);
+        transition.labels = BTreeMap::from([("mount".to_string(), "/".to_string())]);
+        let alert = alertmanager.alert(&transition);
+        assert_eq!(alert["labels"]["mount"], "/");
+        assert!(alert["annotations"].get("mount").is_none());
+
+        alertmanager.checker_labels = Some(false);
+        let alert = alertmanager.alert(&transition);
+        assert!(alert["labels"].get("mount").is_none());
+        assert_eq!(alert["annotations"]["mount"], "/");
+        assert_eq!(alert["annotations"]["state"], "failure");
+    }
+
+    #[test]
+    fn test_resend() {
+        let notifier = Notifier {
+            name: "alertmanager".to_string(),
+            target: Target::Alertmanager(Alertmanager::new("http://127.0.0.1:9093")),
+            max_attempts: None,
+            backoff: None,
+            rate_limit: None,
+        };
+        let now = Instant::now();
+        let failing = Transition::new("f", "t", Some(Outcome::Failure), Outcome::Failure);
+        assert!(notifier.wants(&failing, None, now));
+        assert!(!notifier.wants(&failing, Some(now), now + Duration::from_secs(30)));
+        assert!(notifier.wants(&failing, Some(now), now + Duration::from_secs(60)));
+        let succeeding = Transition::new("f", "t", Some(Outcome::Success), Outcome::Success);
+        assert!(!notifier.wants(&succeeding, None, now));
+    }
+}
@@ -0,0 +1,575 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Display,
+    future::Future,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use handlebars::{handlebars_helper, no_escape, Handlebars};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::{task::JoinSet, time::sleep};
+use tracing::{error, info, warn};
+
+use self::{alertmanager::Alertmanager, smtp::Smtp, webhook::Webhook};
+use crate::{
+    error::Result,
+    task::{Backoff, Outcome},
+};
+
+pub mod alertmanager;
+pub mod smtp;
+pub mod webhook;
+
+/// notifiers of the daemon with their rate limit state
+static NOTIFIERS: Lazy<RwLock<Vec<Arc<Dispatcher>>>> = Lazy::new(|| RwLock::new(vec![]));
+/// notifications in flight, awaited on shutdown
+static SENDING: Lazy<Mutex<JoinSet<()>>> = Lazy::new(|| Mutex::new(JoinSet::new()));
+
+/// A target notified when a task changes state
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Notifier {
+    pub name: String,
+    pub target: Target,
+    /// Max attempts including the first one, default 3
+    pub max_attempts: Option<u32>,
+    /// Backoff between attempts, default fixed 1s
+    pub backoff: Option<Backoff>,
+    /// Notifications over the limit are dropped
+    pub rate_limit: Option<RateLimit>,
+}
+
+// a config value, built once per reload
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Target {
+    Webhook(Webhook),
+    Alertmanager(Alertmanager),
+    Smtp(Smtp),
+}
+
+/// At most max notifications within window(s)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RateLimit {
+    pub window: u64,
+    pub max: u32,
+}
+
+/// The reported state of a task after a run, a transition when the state changed,
+/// the context of templates
+#[derive(Debug, Serialize, Clone)]
+pub struct Transition {
+    pub flow: String,
+    pub task: String,
+    /// none before the first run
+    pub old_state: Option<Outcome>,
+    pub new_state: Outcome,
+    pub message: String,
+    /// stdout and stderr of the checker
+    pub output: String,
+    pub labels: BTreeMap<String, String>,
+    /// RFC 3339 time of the run
+    pub time: String,
+    /// RFC 3339 time the new state began
+    pub since: String,
+}
+
+impl Transition {
+    pub fn new(
+        flow: impl Into<String>,
+        task: impl Into<String>,
+        old_state: Option<Outcome>,
+        new_state: Outcome,
+    ) -> Self {
+        let time = chrono::Utc::now().to_rfc3339();
+        Self {
+            flow: flow.into(),
+            task: task.into(),
+            old_state,
+            new_state,
+            message: String::new(),
+            output: String::new(),
+            labels: BTreeMap::new(),
+            since: time.clone(),
+            time,
+        }
+    }
+
+    /// whether the state changed, a task which succeeds or is skipped from the start
+    /// has not changed
+    pub fn changed(&self) -> bool {
+        match self.old_state {
+            None => self.failing(),
+            Some(old_state) => old_state != self.new_state,
+        }
+    }
+
+    /// whether the task is failing, a skipped task is not checked and resolves its alert
+    pub fn failing(&self) -> bool {
+        !matches!(self.new_state, Outcome::Success | Outcome::Skipped)
+    }
+}
+
+impl Display for Transition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Flow({}) Task({}) {:?} to {:?}",
+            self.flow, self.task, self.old_state, self.new_state
+        )
+    }
+}
+
+handlebars_helper!(json: |value: Json| serde_json::to_string(value).unwrap_or_default());
+
+/// handlebars of the notifier templates, values are not escaped,
+/// `{{json value}}` writes a JSON value
+fn templates() -> Handlebars<'static> {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(no_escape);
+    handlebars.register_helper("json", Box::new(json));
+    handlebars
+}
+
+impl Notifier {
+    pub fn validate(&self) -> Result<()> {
+        match &self.target {
+            Target::Webhook(webhook) => webhook.validate(),
+            Target::Alertmanager(alertmanager) => alertmanager.validate(),
+            Target::Smtp(smtp) => smtp.validate(),
+        }
+    }
+
+    /// the window to batch transitions over into one notification
+    fn digest(&self) -> Option<Duration> {
+        match &self.target {
+            Target::Smtp(smtp) => smtp.digest(),
+            _ => None,
+        }
+    }
+
+    /// whether to send the state of a task, which was last sent at `last_sent`
+    fn wants(&self, transition: &Transition, last_sent: Option<Instant>, now: Instant) -> bool {
+        match &self.target {
+            Target::Webhook(_) | Target::Smtp(_) => transition.changed(),
+            // re-sent while failing, and resolved once
+            Target::Alertmanager(alertmanager) => {
+                transition.changed()
+                    || transition.failing()
+                        && last_sent.map_or(true, |last_sent| {
+                            now.duration_since(last_sent) >= alertmanager.resend_interval()
+                        })
+            }
+        }
+    }
+
+    async fn send_once(&self, transition: &Transition) -> Result<()> {
+        match &self.target {
+            Target::Webhook(webhook) => webhook.send(transition).await,
+            Target::Alertmanager(alertmanager) => alertmanager.send(transition).await,
+            Target::Smtp(smtp) => smtp.send(transition).await,
+        }
+    }
+
+    /// send a transition, retried with the backoff until max attempts
+    pub async fn send(&self, transition: &Transition) -> Result<()> {
+        self.retry(|| self.send_once(transition)).await
+    }
+
+    /// send the transitions within a digest window in one notification, retried like `send`
+    pub async fn send_digest(&self, transitions: &[Transition]) -> Result<()> {
+        match &self.target {
+            Target::Smtp(smtp) => self.retry(|| smtp.send_digest(transitions)).await,
+            _ => {
+                for transition in transitions {
+                    self.send(transition).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn retry<F, Fut>(&self, send: F) -> Result<()>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let max_attempts = self.max_attempts.unwrap_or(3).max(1);
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match send().await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempts >= max_attempts => return Err(e),
+                Err(e) => {
+                    let delay = self
+                        .backoff
+                        .as_ref()
+                        .map_or(Duration::from_secs(1), |backoff| backoff.delay(attempts));
+                    warn!(
+                        "Retrying Notifier({}) in {:?}, attempt {} failed: {}",
+                        self.name, delay, attempts, e
+                    );
+                    sleep(delay).await;
+                }
+            }
+        }
+    }
+}
+
+struct Dispatcher {
+    notifier: Notifier,
+    /// instants of notifications within the rate limit window
+    sent: Mutex<VecDeque<Instant>>,
+    /// instant of the last notification by (flow, task)
+    last_sent: Mutex<HashMap<(String, String), Instant>>,
+    /// transitions waiting for the end of the digest window
+    pending: Mutex<Vec<Transition>>,
+    /// transitions to send by (flow, task), the front one is being sent,
+    /// so a late retry of an older state never overtakes a newer one
+    queues: Mutex<HashMap<(String, String), VecDeque<Transition>>>,
+}
+
+impl Dispatcher {
+    fn new(notifier: Notifier) -> Self {
+        Self {
+            notifier,
+            sent: Mutex::new(VecDeque::new()),
+            last_sent: Mutex::new(HashMap::new()),
+            pending: Mutex::new(vec![]),
+            queues: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// whether the notifier wants the state of a task
+    fn wants(&self, transition: &Transition, now: Instant) -> bool {
+        let key = (transition.flow.clone(), transition.task.clone());
+        let last_sent = self.last_sent.lock().unwrap().get(&key).copied();
+        self.notifier.wants(transition, last_sent, now)
+    }
+
+    /// record the states of tasks as sent at `now`, once they passed the rate limit
+    fn sent(&self, transitions: &[Transition], now: Instant) {
+        let mut last_sent = self.last_sent.lock().unwrap();
+        for transition in transitions {
+            last_sent.insert((transition.flow.clone(), transition.task.clone()), now);
+        }
+    }
+
+    /// whether a notification at `now` is within the rate limit, and record it if so
+    fn allow(&self, now: Instant) -> bool {
+        let Some(limit) = &self.notifier.rate_limit else {
+            return true;
+        };
+        let mut sent = self.sent.lock().unwrap();
+        let window = Duration::from_secs(limit.window);
+        while sent
+            .front()
+            .map_or(false, |first| now.duration_since(*first) >= window)
+        {
+            sent.pop_front();
+        }
+        if sent.len() as u32 >= limit.max {
+            return false;
+        }
+        sent.push_back(now);
+        true
+    }
+}
+
+/// compare notifiers by their serialized form
+fn same(a: &Notifier, b: &Notifier) -> bool {
+    serde_json::to_string(a).ok() == serde_json::to_string(b).ok()
+}
+
+/// replace the notifiers of the daemon, unchanged notifiers keep their rate limit state
+pub fn set_notifiers(notifiers: Vec<Notifier>) {
+    let mut current = NOTIFIERS.write().unwrap();
+    *current = notifiers
+        .into_iter()
+        .map(|notifier| {
+            current
+                .iter()
+                .find(|d| same(&d.notifier, &notifier))
+                .cloned()
+                .unwrap_or_else(|| Arc::new(Dispatcher::new(notifier)))
+        })
+        .collect();
+}
+
+/// send the state of a task after a run to every notifier which wants it, in the background
+pub fn notify(transition: Transition) {
+    let notifiers = NOTIFIERS.read().unwrap();
+    let now = Instant::now();
+    for dispatcher in notifiers.iter() {
+        dispatch(dispatcher, &transition, now);
+    }
+}
+
+fn dispatch(dispatcher: &Arc<Dispatcher>, transition: &Transition, now: Instant) {
+    let name = &dispatcher.notifier.name;
+    if !dispatcher.wants(transition, now) {
+        return;
+    }
+    if let Some(window) = dispatcher.notifier.digest() {
+        let mut pending = dispatcher.pending.lock().unwrap();
+        pending.push(transition.clone());
+        // the first transition of a window schedules the digest
+        if pending.len() == 1 {
+            let dispatcher = dispatcher.clone();
+            tokio::spawn(async move {
+                sleep(window).await;
+                send_digest(&dispatcher).await;
+            });
+        }
+        return;
+    }
+    if !dispatcher.allow(now) {
+        warn!("Notifier({}) is rate limited, dropped {}", name, transition);
+        return;
+    }
+    dispatcher.sent(std::slice::from_ref(transition), now);
+    let key = (transition.flow.clone(), transition.task.clone());
+    let mut queues = dispatcher.queues.lock().unwrap();
+    let queue = queues.entry(key.clone()).or_default();
+    queue.push_back(transition.clone());
+    // the first transition of a task starts sending its queue
+    if queue.len() == 1 {
+        let dispatcher = dispatcher.clone();
+        let mut sending = SENDING.lock().unwrap();
+        while sending.try_join_next().is_some() {}
+        sending.spawn(async move { send_queue(&dispatcher, key).await });
+    }
+}
+
+/// send the queued transitions of a task one after another, until the queue is empty
+async fn send_queue(dispatcher: &Dispatcher, key: (String, String)) {
+    let name = &dispatcher.notifier.name;
+    loop {
+        let Some(transition) = dispatcher
+            .queues
+            .lock()
+            .unwrap()
+            .get(&key)
+            .and_then(|queue| queue.front().cloned())
+        else {
+            return;
+        };
+        match dispatcher.notifier.send(&transition).await {
+            Ok(()) => info!("Notifier({}) sent {}", name, transition),
+            Err(e) => error!("Notifier({}) failed to send {}: {}", name, transition, e),
+        }
+        let mut queues = dispatcher.queues.lock().unwrap();
+        let Some(queue) = queues.get_mut(&key) else {
+            return;
+        };
+        queue.pop_front();
+        if queue.is_empty() {
+            queues.remove(&key);
+            return;
+        }
+    }
+}
+
+/// send the pending digests at once instead of at the end of their windows,
+/// and wait for the notifications in flight, e.g. on shutdown
+pub async fn flush() {
+    let dispatchers = NOTIFIERS.read().unwrap().clone();
+    for dispatcher in dispatchers.iter() {
+        send_digest(dispatcher).await;
+    }
+    loop {
+        let mut sending = std::mem::take(&mut *SENDING.lock().unwrap());
+        if sending.is_empty() {
+            return;
+        }
+        while sending.join_next().await.is_some() {}
+    }
+}
+
+async fn send_digest(dispatcher: &Dispatcher) {
+    let name = &dispatcher.notifier.name;
+    let transitions = std::mem::take(&mut *dispatcher.pending.lock().unwrap());
+    // flushed before the end of the window
+    if transitions.is_empty() {
+        return;
+    }
+    let now = Instant::now();
+    if !dispatcher.allow(now) {
+        warn!(
+            "Notifier({}) is rate limited, dropped a digest of {} transitions",
+            name,
+            transitions.len()
+        );
+        return;
+    }
+    dispatcher.sent(&transitions, now);
+    match dispatcher.notifier.send_digest(&transitions).await {
+        Ok(()) => info!(
+            "Notifier({}) sent a digest of {} transitions",
+            name,
+            transitions.len()
+        ),
+        Err(e) => error!(
+            "Notifier({}) failed to send a digest of {} transitions: {}",
+            name,
+            transitions.len(),
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{http::StatusCode, routing::post, Router};
+
+    use super::*;
+
+    fn notifier(url: String) -> Notifier {
+        Notifier {
+            name: "hook".to_string(),
+            target: Target::Webhook(Webhook::new(url)),
+            max_attempts: None,
+            backoff: Some(Backoff::Fixed { delay: 0 }),
+            rate_limit: None,
+        }
+    }
+
+    #[test]
+    fn test_transition() {
+        let transition = |old_state, new_state| Transition::new("f", "t", old_state, new_state);
+        assert!(!transition(None, Outcome::Success).changed());
+        assert!(!transition(None, Outcome::Skipped).changed());
+        assert!(transition(None, Outcome::Failure).changed());
+        let skipped = transition(Some(Outcome::Failure), Outcome::Skipped);
+        assert!(skipped.changed());
+        assert!(!skipped.failing());
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut notifier = notifier("http://127.0.0.1/hook".to_string());
+        notifier.rate_limit = Some(RateLimit { window: 60, max: 2 });
+        let dispatcher = Dispatcher::new(notifier);
+        let now = Instant::now();
+        assert!(dispatcher.allow(now));
+        assert!(dispatcher.allow(now + Duration::from_secs(1)));
+        assert!(!dispatcher.allow(now + Duration::from_secs(2)));
+        assert!(dispatcher.allow(now + Duration::from_secs(60)));
+        assert!(!dispatcher.allow(now + Duration::from_secs(60)));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_not_sent() {
+        let mut notifier = notifier("http://127.0.0.1:1/hook".to_string());
+        notifier.target = Target::Alertmanager(Alertmanager::new("http://127.0.0.1:1"));
+        notifier.max_attempts = Some(1);
+        notifier.rate_limit = Some(RateLimit { window: 60, max: 1 });
+        let dispatcher = Arc::new(Dispatcher::new(notifier));
+        let now = Instant::now();
+        let failing = |task| Transition::new("f", task, Some(Outcome::Success), Outcome::Failure);
+        dispatch(&dispatcher, &failing("a"), now);
+        dispatch(&dispatcher, &failing("b"), now);
+        // the dropped alert of b is not recorded as sent, a later failure of b is not held back
+        let last_sent = dispatcher.last_sent.lock().unwrap();
+        assert!(last_sent.contains_key(&("f".to_string(), "a".to_string())));
+        assert!(!last_sent.contains_key(&("f".to_string(), "b".to_string())));
+    }
+
+    type Bodies = Arc<Mutex<Vec<String>>>;
+
+    /// a webhook which fails the first request, returns its addr and the bodies it received
+    fn hook() -> std::result::Result<(SocketAddr, Bodies), Box<dyn std::error::Error>> {
+        let bodies = Arc::new(Mutex::new(vec![]));
+        let app = Router::new().route(
+            "/hook",
+            post({
+                let bodies = bodies.clone();
+                move |body: String| {
+                    let mut bodies = bodies.lock().unwrap();
+                    bodies.push(body);
+                    let failed = bodies.len() == 1;
+                    async move {
+                        match failed {
+                            true => StatusCode::SERVICE_UNAVAILABLE,
+                            false => StatusCode::OK,
+                        }
+                    }
+                }
+            }),
+        );
+        let server = axum::Server::bind(&"127.0.0.1:0".parse()?).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        Ok((addr, bodies))
+    }
+
+    #[tokio::test]
+    async fn test_send_order() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let (addr, bodies) = hook()?;
+        let mut notifier = notifier(format!("http://{}/hook", addr));
+        notifier.backoff = Some(Backoff::Fixed { delay: 1 });
+        let dispatcher = Arc::new(Dispatcher::new(notifier));
+        let now = Instant::now();
+        dispatch(
+            &dispatcher,
+            &Transition::new("web", "homepage", None, Outcome::Failure),
+            now,
+        );
+        dispatch(
+            &dispatcher,
+            &Transition::new("web", "homepage", Some(Outcome::Failure), Outcome::Success),
+            now,
+        );
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        // the retried failure is delivered before the recovery
+        let states = bodies
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|body| serde_json::from_str::<serde_json::Value>(body))
+            .collect::<std::result::Result<Vec<_>, _>>()?
+            .into_iter()
+            .map(|body| body["new_state"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(states, vec!["failure", "failure", "success"]);
+        assert!(dispatcher.queues.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flush() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let (addr, bodies) = hook()?;
+        let mut notifier = notifier(format!("http://{}/hook", addr));
+        notifier.backoff = Some(Backoff::Fixed { delay: 1 });
+        let dispatcher = Arc::new(Dispatcher::new(notifier));
+        let transition = Transition::new("web", "api", None, Outcome::Failure);
+        dispatch(&dispatcher, &transition, Instant::now());
+        // the retry of the failed send is awaited
+        tokio::time::timeout(Duration::from_secs(5), flush()).await?;
+        assert_eq!(bodies.lock().unwrap().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_retry() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let (addr, bodies) = hook()?;
+
+        let mut notifier = notifier(format!("http://{}/hook", addr));
+        let transition = Transition::new("web", "homepage", None, Outcome::Failure);
+        notifier.send(&transition).await?;
+        assert_eq!(bodies.lock().unwrap().len(), 2);
+        let body: serde_json::Value = serde_json::from_str(&bodies.lock().unwrap()[1])?;
+        assert_eq!(body["task"], "homepage");
+        assert_eq!(body["old_state"], serde_json::Value::Null);
+
+        notifier.max_attempts = Some(1);
+        notifier.target = Target::Webhook(Webhook::new(format!("http://{}/unknown", addr)));
+        assert!(notifier.send(&transition).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,572 @@
+use std::{fmt::Display, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use handlebars::Template;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_native_tls::{native_tls, TlsConnector};
+
+use super::{templates, Transition};
+use crate::{app_error, error::Result};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Smtp {
+    pub host: String,
+    /// default 587 with starttls, 465 with tls, 25 without
+    pub port: Option<u16>,
+    /// default starttls
+    pub tls: Option<SmtpTls>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sender like "Sertus <sertus@example.com>"
+    pub from: String,
+    pub to: Vec<String>,
+    /// Handlebars template of the subject
+    pub subject: Option<String>,
+    /// Handlebars template of the plain text body
+    pub body: Option<String>,
+    /// Batch the transitions within digest(s) into one email
+    pub digest: Option<u64>,
+    /// Timeout of sending an email, default 30(s)
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// plain text
+    None,
+    /// upgrade a plain text connection
+    Starttls,
+    /// implicit TLS
+    Tls,
+}
+
+const SUBJECT: &str = "[sertus] {{flow}}/{{task}} is {{new_state}}";
+const BODY: &str =
+    "Flow({{flow}}) Task({{task}}) is {{new_state}}{{#if old_state}}, was {{old_state}}{{/if}}
+since {{since}}
+
+{{message}}
+{{#if output}}
+{{output}}
+{{/if}}";
+const DIGEST_SUBJECT: &str = "[sertus] {{len transitions}} task state changes";
+const DIGEST_BODY: &str = "{{#each transitions}}
+{{time}} Flow({{flow}}) Task({{task}}) is {{new_state}}{{#if old_state}}, was {{old_state}}{{/if}}: {{message}}
+{{/each}}";
+
+/// the address of a mailbox like "Name <user@host>"
+fn address(mailbox: &str) -> &str {
+    match (mailbox.find('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+/// a header value on one line, RFC 2047 encoded unless it is ascii
+fn header_value(value: &str) -> String {
+    let value = value.replace(['\r', '\n'], " ");
+    match value.is_ascii() {
+        true => value,
+        false => format!("=?utf-8?B?{}?=", STANDARD.encode(value)),
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    match std::str::from_utf8(&buf[..len]) {
+        Ok(hostname) if ret == 0 && !hostname.is_empty() => hostname.to_string(),
+        _ => "localhost".to_string(),
+    }
+}
+
+impl Smtp {
+    pub fn new(host: impl Into<String>, from: impl Into<String>, to: Vec<String>) -> Self {
+        Self {
+            host: host.into(),
+            port: None,
+            tls: None,
+            username: None,
+            password: None,
+            from: from.into(),
+            to,
+            subject: None,
+            body: None,
+            digest: None,
+            timeout: None,
+        }
+    }
+
+    fn tls(&self) -> SmtpTls {
+        self.tls.unwrap_or(SmtpTls::Starttls)
+    }
+
+    fn port(&self) -> u16 {
+        self.port.unwrap_or(match self.tls() {
+            SmtpTls::None => 25,
+            SmtpTls::Starttls => 587,
+            SmtpTls::Tls => 465,
+        })
+    }
+
+    pub fn digest(&self) -> Option<Duration> {
+        self.digest.map(Duration::from_secs)
+    }
+
+    /// check the addresses, the credentials and the templates
+    pub fn validate(&self) -> Result<()> {
+        if self.host.is_empty() {
+            return Err(app_error!("smtp host is empty"));
+        }
+        if self.to.is_empty() {
+            return Err(app_error!("smtp has no recipients"));
+        }
+        for mailbox in std::iter::once(&self.from).chain(&self.to) {
+            let address = address(mailbox);
+            if !address.contains('@') || address.contains(['\r', '\n', ' ']) {
+                return Err(app_error!("invalid email address {:?}", mailbox));
+            }
+        }
+        if self.username.is_some() != self.password.is_some() {
+            return Err(app_error!(
+                "smtp username and password must be set together"
+            ));
+        }
+        if self.username.is_some() && self.tls() == SmtpTls::None {
+            return Err(app_error!(
+                "smtp credentials would be sent in plain text with tls = \"none\""
+            ));
+        }
+        if self.digest == Some(0) {
+            return Err(app_error!("digest must be greater than 0"));
+        }
+        for (name, template) in [("subject", &self.subject), ("body", &self.body)] {
+            if let Some(template) = template {
+                Template::compile(template)
+                    .map_err(|e| app_error!("invalid {} template: {}", name, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// the subject and the body of a transition, or of a digest with the `transitions`
+    fn render(&self, context: &serde_json::Value, digest: bool) -> Result<(String, String)> {
+        let (subject, body) = match digest {
+            true => (DIGEST_SUBJECT, DIGEST_BODY),
+            false => (SUBJECT, BODY),
+        };
+        let handlebars = templates();
+        let render = |name, template: &str| {
+            handlebars
+                .render_template(template, context)
+                .map_err(|e| app_error!("render {} template: {}", name, e))
+        };
+        Ok((
+            render("subject", self.subject.as_deref().unwrap_or(subject))?,
+            render("body", self.body.as_deref().unwrap_or(body))?,
+        ))
+    }
+
+    /// the email with CRLF line endings, before dot-stuffing
+    fn message(&self, subject: &str, body: &str) -> String {
+        let body = body.lines().collect::<Vec<_>>().join("\r\n");
+        let (encoding, body) = match body.is_ascii() {
+            true => ("7bit", body),
+            false => {
+                let encoded = STANDARD.encode(body);
+                let lines = encoded.as_bytes().chunks(76);
+                let lines = lines.map(|line| String::from_utf8_lossy(line).into_owned());
+                ("base64", lines.collect::<Vec<_>>().join("\r\n"))
+            }
+        };
+        format!(
+            "Date: {}\r\nFrom: {}\r\nTo: {}\r\nSubject: {}\r\nMIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: {}\r\n\r\n{}\r\n",
+            chrono::Local::now().to_rfc2822(),
+            header_value(&self.from),
+            header_value(&self.to.join(", ")),
+            header_value(subject),
+            encoding,
+            body
+        )
+    }
+
+    pub async fn send(&self, transition: &Transition) -> Result<()> {
+        let (subject, body) = self.render(&serde_json::to_value(transition)?, false)?;
+        self.send_email(&subject, &body).await
+    }
+
+    pub async fn send_digest(&self, transitions: &[Transition]) -> Result<()> {
+        let (subject, body) = self.render(&json!({ "transitions": transitions }), true)?;
+        self.send_email(&subject, &body).await
+    }
+
+    async fn send_email(&self, subject: &str, body: &str) -> Result<()> {
+        let message = self.message(subject, body);
+        timeout(
+            Duration::from_secs(self.timeout.unwrap_or(30)),
+            self.transaction(&message),
+        )
+        .await
+        .map_err(|_| app_error!("smtp {} timed out", self))?
+    }
+
+    async fn transaction(&self, message: &str) -> Result<()> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port())).await?;
+        let mut connection = match self.tls() {
+            SmtpTls::Tls => Connection::new(Box::new(stream)).tls(&self.host).await?,
+            _ => Connection::new(Box::new(stream)),
+        };
+        connection.reply(2, "greeting").await?;
+        let hostname = hostname();
+        let mut extensions = connection.command(&format!("EHLO {}", hostname), 2).await?;
+        if self.tls() == SmtpTls::Starttls {
+            connection.command("STARTTLS", 2).await?;
+            connection = connection.tls(&self.host).await?;
+            extensions = connection.command(&format!("EHLO {}", hostname), 2).await?;
+        }
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            let mechanisms = extensions
+                .lines()
+                .find_map(|line| line.strip_prefix("AUTH "))
+                .unwrap_or_default()
+                .split_whitespace()
+                .collect::<Vec<_>>();
+            // credentials are only sent with a mechanism the server advertises
+            if mechanisms.contains(&"PLAIN") {
+                let credentials = format!("\0{}\0{}", username, password);
+                let command = format!("AUTH PLAIN {}", STANDARD.encode(credentials));
+                connection.secret(&command, 2).await?;
+            } else if mechanisms.contains(&"LOGIN") {
+                connection.command("AUTH LOGIN", 3).await?;
+                connection.secret(&STANDARD.encode(username), 3).await?;
+                connection.secret(&STANDARD.encode(password), 2).await?;
+            } else {
+                connection.command("QUIT", 2).await.ok();
+                return Err(app_error!(
+                    "smtp {} does not advertise AUTH PLAIN or LOGIN",
+                    self
+                ));
+            }
+        }
+        connection
+            .command(&format!("MAIL FROM:<{}>", address(&self.from)), 2)
+            .await?;
+        for to in &self.to {
+            connection
+                .command(&format!("RCPT TO:<{}>", address(to)), 2)
+                .await?;
+        }
+        connection.command("DATA", 3).await?;
+        let mut data = String::with_capacity(message.len() + 5);
+        for line in message.split_inclusive("\r\n") {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+        }
+        data.push_str(".\r\n");
+        connection.write(&data).await?;
+        connection.reply(2, "DATA").await?;
+        // the email is accepted whatever the server replies to QUIT
+        connection.command("QUIT", 2).await.ok();
+        Ok(())
+    }
+}
+
+impl Display for Smtp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host, self.port())
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// a connection to a SMTP server, plain text or TLS
+struct Connection {
+    stream: BufStream<Box<dyn Io>>,
+}
+
+impl Connection {
+    fn new(stream: Box<dyn Io>) -> Self {
+        Self {
+            stream: BufStream::new(stream),
+        }
+    }
+
+    async fn tls(self, host: &str) -> Result<Self> {
+        let connector = native_tls::TlsConnector::new().map_err(|e| app_error!("tls: {}", e))?;
+        let stream = TlsConnector::from(connector)
+            .connect(host, self.stream.into_inner())
+            .await
+            .map_err(|e| app_error!("tls handshake: {}", e))?;
+        Ok(Self::new(Box::new(stream)))
+    }
+
+    async fn write(&mut self, data: &str) -> Result<()> {
+        self.stream.write_all(data.as_bytes()).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// read a reply of one or more lines, which is an error unless the code is in
+    /// `class`, like 2 for 250, returns the text of the lines
+    async fn reply(&mut self, class: u16, name: &str) -> Result<String> {
+        let mut text = String::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(app_error!("smtp connection closed after {}", name));
+            }
+            let line = line.trim_end();
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse::<u16>().ok())
+                .ok_or_else(|| app_error!("invalid smtp reply to {}: {:?}", name, line))?;
+            text.push_str(line.get(4..).unwrap_or_default());
+            text.push('\n');
+            if line.as_bytes().get(3) == Some(&b'-') {
+                continue;
+            }
+            if code / 100 != class {
+                return Err(app_error!(
+                    "smtp {} responded {} {}",
+                    name,
+                    code,
+                    text.trim_end()
+                ));
+            }
+            return Ok(text);
+        }
+    }
+
+    async fn command(&mut self, command: &str, class: u16) -> Result<String> {
+        self.write(&format!("{}\r\n", command)).await?;
+        self.reply(class, command).await
+    }
+
+    /// a command with credentials, which are not written to errors
+    async fn secret(&mut self, command: &str, class: u16) -> Result<String> {
+        self.write(&format!("{}\r\n", command)).await?;
+        self.reply(class, "AUTH").await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        notifier::{dispatch, send_digest, Dispatcher, Notifier, Target},
+        task::Outcome,
+    };
+
+    type Sessions = Arc<Mutex<Vec<(Vec<String>, String)>>>;
+
+    async fn sink() -> std::io::Result<(u16, Sessions)> {
+        sink_with(b"250-sink\r\n250 AUTH PLAIN\r\n").await
+    }
+
+    /// a SMTP sink which accepts every email and replies `ehlo` to EHLO,
+    /// returns the commands and the data of each session recorded on QUIT
+    async fn sink_with(ehlo: &'static [u8]) -> std::io::Result<(u16, Sessions)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let sessions = Arc::new(Mutex::new(vec![]));
+        tokio::spawn({
+            let sessions = sessions.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let sessions = sessions.clone();
+                    tokio::spawn(async move {
+                        let (read, mut write) = stream.into_split();
+                        let mut lines = BufReader::new(read).lines();
+                        let (mut commands, mut data) = (vec![], String::new());
+                        write.write_all(b"220 sink\r\n").await?;
+                        while let Some(line) = lines.next_line().await? {
+                            let reply: &[u8] = match line.as_str() {
+                                l if l.starts_with("EHLO") => ehlo,
+                                l if l.starts_with("AUTH") => b"235 ok\r\n",
+                                "DATA" => {
+                                    write.write_all(b"354 go ahead\r\n").await?;
+                                    while let Some(line) = lines.next_line().await? {
+                                        if line == "." {
+                                            break;
+                                        }
+                                        data.push_str(&line);
+                                        data.push('\n');
+                                    }
+                                    b"250 queued\r\n"
+                                }
+                                "QUIT" => {
+                                    let session = (commands.clone(), data.clone());
+                                    sessions.lock().unwrap().push(session);
+                                    b"221 bye\r\n"
+                                }
+                                _ => b"250 ok\r\n",
+                            };
+                            commands.push(line);
+                            write.write_all(reply).await?;
+                        }
+                        std::io::Result::Ok(())
+                    });
+                }
+            }
+        });
+        Ok((port, sessions))
+    }
+
+    fn notifier(port: u16) -> Notifier {
+        let mut smtp = Smtp::new(
+            "127.0.0.1",
+            "Sertus <sertus@example.com>",
+            vec!["ops@example.com".to_string(), "dev@example.com".to_string()],
+        );
+        smtp.port = Some(port);
+        smtp.tls = Some(SmtpTls::None);
+        Notifier {
+            name: "email".to_string(),
+            target: Target::Smtp(smtp),
+            max_attempts: Some(1),
+            backoff: None,
+            rate_limit: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_smtp() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let (port, sessions) = sink().await?;
+        let mut notifier = notifier(port);
+        let Target::Smtp(smtp) = &mut notifier.target else {
+            unreachable!()
+        };
+        smtp.username = Some("sertus".to_string());
+        smtp.password = Some("secret".to_string());
+        // the sink is plain text, where credentials are refused by the validation
+        assert!(smtp.validate().is_err());
+        let mut transition =
+            Transition::new("web", "homepage", Some(Outcome::Success), Outcome::Failure);
+        transition.message = "status 503\n.hidden".to_string();
+        notifier.send(&transition).await?;
+
+        let (commands, data) = sessions.lock().unwrap()[0].clone();
+        assert_eq!(
+            commands[2..],
+            [
+                "MAIL FROM:<sertus@example.com>",
+                "RCPT TO:<ops@example.com>",
+                "RCPT TO:<dev@example.com>",
+                "DATA"
+            ]
+        );
+        let credentials = STANDARD.decode(commands[1].trim_start_matches("AUTH PLAIN "))?;
+        assert_eq!(credentials, b"\0sertus\0secret");
+        assert!(data.contains("To: ops@example.com, dev@example.com\n"));
+        assert!(data.contains("Subject: [sertus] web/homepage is failure\n"));
+        assert!(data.contains("Flow(web) Task(homepage) is failure, was success\n"));
+        // dot-stuffed
+        assert!(data.contains("\n..hidden\n"));
+
+        // no credentials to a server which does not advertise AUTH
+        let (port, sessions) = sink_with(b"250 sink\r\n").await?;
+        let Target::Smtp(smtp) = &mut notifier.target else {
+            unreachable!()
+        };
+        smtp.port = Some(port);
+        let err = notifier.send(&transition).await.unwrap_err().to_string();
+        assert!(err.contains("does not advertise AUTH"), "{}", err);
+        let (commands, _) = sessions.lock().unwrap()[0].clone();
+        assert!(!commands.iter().any(|c| c.starts_with("AUTH")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_digest() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let (port, sessions) = sink().await?;
+        let mut notifier = notifier(port);
+        let Target::Smtp(smtp) = &mut notifier.target else {
+            unreachable!()
+        };
+        smtp.digest = Some(1);
+        let dispatcher = Arc::new(Dispatcher::new(notifier));
+        let notify = |old_state, new_state| {
+            let transition = Transition::new("net", "ping", old_state, new_state);
+            dispatch(&dispatcher, &transition, Instant::now());
+        };
+        notify(None, Outcome::Failure);
+        notify(Some(Outcome::Failure), Outcome::Success);
+        notify(Some(Outcome::Success), Outcome::Success);
+        notify(Some(Outcome::Success), Outcome::Flapping);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        let sessions = sessions.lock().unwrap().clone();
+        assert_eq!(sessions.len(), 1);
+        let data = &sessions[0].1;
+        assert!(data.contains("Subject: [sertus] 3 task state changes\n"));
+        assert!(data.contains("Flow(net) Task(ping) is failure: \n"));
+        assert!(data.contains("Flow(net) Task(ping) is flapping, was success: \n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_digest_flush() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let (port, sessions) = sink().await?;
+        let mut notifier = notifier(port);
+        let Target::Smtp(smtp) = &mut notifier.target else {
+            unreachable!()
+        };
+        smtp.digest = Some(60);
+        let dispatcher = Arc::new(Dispatcher::new(notifier));
+        let transition = Transition::new("net", "ping", None, Outcome::Failure);
+        dispatch(&dispatcher, &transition, Instant::now());
+        // a flush sends the pending transitions before the window ends, once
+        send_digest(&dispatcher).await;
+        send_digest(&dispatcher).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let sessions = sessions.lock().unwrap().clone();
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions[0]
+            .1
+            .contains("Subject: [sertus] 1 task state changes\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_message() {
+        let mut smtp = Smtp::new("smtp.example.com", "sertus@example.com", vec![]);
+        assert!(smtp.validate().is_err());
+        smtp.to = vec!["Ops <ops@example.com>".to_string()];
+        assert!(smtp.validate().is_ok());
+        assert_eq!(smtp.port(), 587);
+        assert_eq!(address(&smtp.to[0]), "ops@example.com");
+        smtp.username = Some("sertus".to_string());
+        assert!(smtp.validate().is_err());
+
+        let message = smtp.message("磁盘\r\nBcc: x", "ünïcode");
+        assert!(message.contains(&format!(
+            "Subject: =?utf-8?B?{}?=\r\n",
+            STANDARD.encode("磁盘  Bcc: x")
+        )));
+        assert!(message.ends_with(&format!("\r\n\r\n{}\r\n", STANDARD.encode("ünïcode"))));
+    }
+}
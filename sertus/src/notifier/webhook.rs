@@ -0,0 +1,133 @@
+use std::{collections::BTreeMap, fmt::Display, str::FromStr, time::Duration};
+
+use handlebars::Template;
+use reqwest::{header::CONTENT_TYPE, Client, Method, Url};
+use serde::{Deserialize, Serialize};
+
+use super::{templates, Transition};
+use crate::{app_error, error::Result};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Webhook {
+    pub url: String,
+    /// HTTP method, default POST
+    pub method: Option<String>,
+    pub headers: Option<BTreeMap<String, String>>,
+    /// Handlebars template of the body, default the transition as JSON
+    pub body: Option<String>,
+    /// Request timeout, default 10(s)
+    pub timeout: Option<u64>,
+}
+
+impl Webhook {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            method: None,
+            headers: None,
+            body: None,
+            timeout: None,
+        }
+    }
+
+    fn method(&self) -> Result<Method> {
+        let method = self.method.as_deref().unwrap_or("POST").to_uppercase();
+        Method::from_str(&method).map_err(|e| app_error!("invalid method {}: {}", method, e))
+    }
+
+    /// check the url, the method and the body template
+    pub fn validate(&self) -> Result<()> {
+        match Url::parse(&self.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            Ok(url) => {
+                return Err(app_error!(
+                    "invalid webhook url {:?}: unsupported scheme {}",
+                    self.url,
+                    url.scheme()
+                ))
+            }
+            Err(e) => return Err(app_error!("invalid webhook url {:?}: {}", self.url, e)),
+        }
+        self.method()?;
+        if let Some(body) = &self.body {
+            Template::compile(body).map_err(|e| app_error!("invalid body template: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// the body of a transition, values are not escaped, `{{json value}}` writes a JSON value
+    pub fn render(&self, transition: &Transition) -> Result<String> {
+        let Some(body) = &self.body else {
+            return Ok(serde_json::to_string(transition)?);
+        };
+        templates()
+            .render_template(body, transition)
+            .map_err(|e| app_error!("render body template: {}", e))
+    }
+
+    pub async fn send(&self, transition: &Transition) -> Result<()> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(self.timeout.unwrap_or(10)))
+            .build()?;
+        let mut request = client
+            .request(self.method()?, &self.url)
+            .header(CONTENT_TYPE, "application/json");
+        for (k, v) in self.headers.iter().flatten() {
+            request = request.header(k, v);
+        }
+        let response = request.body(self.render(transition)?).send().await?;
+        if !response.status().is_success() {
+            return Err(app_error!(
+                "webhook {} responded {}: {}",
+                self.url,
+                response.status(),
+                response.text().await.unwrap_or_default()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Display for Webhook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            self.method.as_deref().unwrap_or("POST"),
+            self.url
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::Outcome;
+
+    #[test]
+    fn test_render() -> Result<()> {
+        let mut transition =
+            Transition::new("web", "homepage", Some(Outcome::Success), Outcome::Failure);
+        transition.message = "status \"503\"".to_string();
+        transition.labels = BTreeMap::from([("host".to_string(), "a".to_string())]);
+        let mut webhook = Webhook::new("http://127.0.0.1/hook");
+        webhook.body =
+            Some("{{flow}}/{{task}} {{old_state}} -> {{new_state}} on {{labels.host}}".to_string());
+        assert_eq!(
+            webhook.render(&transition)?,
+            "web/homepage success -> failure on a"
+        );
+        webhook.body = Some(r#"{"text": {{json message}} }"#.to_string());
+        let body: serde_json::Value = serde_json::from_str(&webhook.render(&transition)?)?;
+        assert_eq!(body["text"], "status \"503\"");
+
+        webhook.body = None;
+        let body: serde_json::Value = serde_json::from_str(&webhook.render(&transition)?)?;
+        assert_eq!(body["new_state"], "failure");
+
+        webhook.body = Some("{{#if}}".to_string());
+        assert!(webhook.validate().is_err());
+        assert!(Webhook::new("ftp://127.0.0.1").validate().is_err());
+        Ok(())
+    }
+}
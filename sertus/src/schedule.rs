@@ -0,0 +1,148 @@
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local, TimeZone, Utc};
+use chrono_tz::Tz;
+use rand::Rng;
+
+use crate::{app_error, error::Result};
+
+/// When a flow runs, either a cron expression or an interval
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    cron: Option<(cron::Schedule, Timezone)>,
+    interval: Duration,
+    fixed_rate: bool,
+    jitter: Duration,
+}
+
+#[derive(Debug, Clone)]
+enum Timezone {
+    Local,
+    Tz(Tz),
+}
+
+impl Scheduler {
+    /// `schedule` is a cron expression with 5 fields (minute precision) or 6, 7 fields (with seconds, years)
+    pub fn new(
+        interval: u64,
+        schedule: Option<&str>,
+        timezone: Option<&str>,
+        fixed_rate: bool,
+        jitter: u64,
+    ) -> Result<Self> {
+        let cron = match schedule {
+            Some(expression) => {
+                let normalized = match expression.split_whitespace().count() {
+                    5 => format!("0 {}", expression),
+                    _ => expression.to_string(),
+                };
+                let cron = cron::Schedule::from_str(&normalized)
+                    .map_err(|e| app_error!("invalid schedule {:?}: {}", expression, e))?;
+                let timezone = match timezone {
+                    Some(name) => Timezone::Tz(
+                        name.parse::<Tz>()
+                            .map_err(|e| app_error!("invalid timezone {:?}: {}", name, e))?,
+                    ),
+                    None => Timezone::Local,
+                };
+                Some((cron, timezone))
+            }
+            None => None,
+        };
+        if cron.is_none() && interval == 0 {
+            return Err(app_error!("interval must be greater than 0"));
+        }
+        Ok(Self {
+            cron,
+            interval: Duration::from_secs(interval),
+            fixed_rate,
+            jitter: Duration::from_secs(jitter),
+        })
+    }
+
+    /// the instant of the first run, immediately unless a cron expression is set
+    pub fn first(&self) -> Instant {
+        match &self.cron {
+            Some(_) => self.next_cron(),
+            None => Instant::now(),
+        }
+    }
+
+    /// the instant of the next run, `planned` is the planned instant of the previous run
+    pub fn next(&self, planned: Instant) -> Instant {
+        let now = Instant::now();
+        match &self.cron {
+            Some(_) => self.next_cron(),
+            None if self.fixed_rate => {
+                // skip the missed runs instead of running them in a burst
+                let mut next = planned + self.interval;
+                while next <= now {
+                    next += self.interval;
+                }
+                next
+            }
+            None => now + self.interval,
+        }
+    }
+
+    /// random delay in [0, jitter] added to a planned instant
+    pub fn jitter(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return Duration::ZERO;
+        }
+        Duration::from_millis(rand::thread_rng().gen_range(0..=self.jitter.as_millis() as u64))
+    }
+
+    fn next_cron(&self) -> Instant {
+        let Some((cron, timezone)) = &self.cron else {
+            return Instant::now() + self.interval;
+        };
+        let next = match timezone {
+            Timezone::Local => next_after(cron, &Local),
+            Timezone::Tz(tz) => next_after(cron, tz),
+        };
+        match next {
+            Some(next) => Instant::now() + (next - Utc::now()).to_std().unwrap_or_default(),
+            // the schedule has no upcoming time, e.g. a year in the past
+            None => Instant::now() + Duration::from_secs(u32::MAX as u64),
+        }
+    }
+}
+
+fn next_after<Z: TimeZone>(cron: &cron::Schedule, timezone: &Z) -> Option<DateTime<Utc>> {
+    cron.after(&Utc::now().with_timezone(timezone))
+        .next()
+        .map(|next| next.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cron_scheduler() {
+        let scheduler =
+            Scheduler::new(3, Some("*/5 * * * *"), Some("Asia/Shanghai"), false, 0).unwrap();
+        let delay = scheduler.first() - Instant::now();
+        assert!(delay <= Duration::from_secs(5 * 60));
+
+        assert!(Scheduler::new(3, Some("* * *"), None, false, 0).is_err());
+        assert!(Scheduler::new(3, Some("0 2 * * *"), Some("Mars/Base"), false, 0).is_err());
+    }
+
+    #[test]
+    fn test_fixed_rate_scheduler() {
+        let scheduler = Scheduler::new(10, None, None, true, 0).unwrap();
+        let planned = Instant::now();
+        assert_eq!(scheduler.next(planned), planned + Duration::from_secs(10));
+        // missed runs are skipped
+        let late = planned - Duration::from_secs(25);
+        assert_eq!(scheduler.next(late), late + Duration::from_secs(30));
+
+        let scheduler = Scheduler::new(10, None, None, false, 2).unwrap();
+        assert!(scheduler.jitter() <= Duration::from_secs(2));
+    }
+}
@@ -1,10 +1,27 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant, SystemTime},
+};
+
 use serde::{Deserialize, Serialize};
 
-use crate::checker::Checker;
+use crate::checker::{Checker, Status};
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Task {
     pub name: String,
     pub checker: Checker,
+    /// Interval of the task in seconds, default runs with the flow schedule
+    pub interval: Option<u64>,
+    /// Timeout of the checker in seconds, default the flow timeout
+    pub timeout: Option<u64>,
+    pub retry: Option<Retry>,
+    /// Consecutive failures before the task is reported as failed, default 1
+    pub failure_threshold: Option<u32>,
+    /// Consecutive successes before the task is reported as succeeded, default 1
+    pub success_threshold: Option<u32>,
+    pub flap: Option<FlapDetection>,
+    /// Names of tasks in the same flow which must succeed before this task runs
+    pub depends_on: Option<Vec<String>>,
 }
 
 impl Task {
@@ -12,8 +29,302 @@ impl Task {
         Self {
             name: name.into(),
             checker,
+            interval: None,
+            timeout: None,
+            retry: None,
+            failure_threshold: None,
+            success_threshold: None,
+            flap: None,
+            depends_on: None,
         }
     }
 }
 
 pub type Tasks = Vec<Task>;
+
+/// Outcome of a task, the value is reported by gauge sertus_flow_task_status
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+    Error,
+    Timeout,
+    /// Reported by flap detection only
+    Flapping,
+    /// A dependency of the task did not succeed
+    Skipped,
+}
+
+impl Outcome {
+    pub fn value(&self) -> f64 {
+        match self {
+            Outcome::Success => 1.0,
+            Outcome::Failure => 0.0,
+            Outcome::Error => -1.0,
+            Outcome::Timeout => -2.0,
+            Outcome::Flapping => -3.0,
+            Outcome::Skipped => -4.0,
+        }
+    }
+
+    /// the `outcome` label of sertus_flow_task_runs_total
+    pub fn label(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+            Outcome::Error => "error",
+            Outcome::Timeout => "timeout",
+            Outcome::Flapping => "flapping",
+            Outcome::Skipped => "skipped",
+        }
+    }
+}
+
+impl From<Status> for Outcome {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Ok => Outcome::Success,
+            Status::Warning | Status::Critical => Outcome::Failure,
+            Status::Unknown => Outcome::Error,
+            Status::Timeout => Outcome::Timeout,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Retry {
+    /// Max attempts including the first one, default 3
+    pub max_attempts: Option<u32>,
+    /// Backoff between attempts, default fixed 1s
+    pub backoff: Option<Backoff>,
+    /// Outcomes to retry, default failure, error and timeout
+    pub on: Option<Vec<Outcome>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Backoff {
+    /// Wait delay(s) between attempts
+    Fixed { delay: u64 },
+    /// Wait initial(s) and multiply it after each attempt, up to max(s)
+    Exponential {
+        initial: u64,
+        multiplier: Option<f64>,
+        max: Option<u64>,
+    },
+}
+
+impl Backoff {
+    /// delay before the next attempt after `attempts` attempts
+    pub fn delay(&self, attempts: u32) -> Duration {
+        match *self {
+            Backoff::Fixed { delay } => Duration::from_secs(delay),
+            Backoff::Exponential {
+                initial,
+                multiplier,
+                max,
+            } => {
+                let secs = initial as f64
+                    * multiplier
+                        .unwrap_or(2.0)
+                        .powi(attempts.saturating_sub(1) as i32);
+                Duration::from_secs_f64(max.map_or(secs, |max| secs.min(max as f64)))
+            }
+        }
+    }
+}
+
+impl Retry {
+    /// whether to retry after `attempts` attempts ended with `outcome`
+    pub fn should_retry(&self, outcome: Outcome, attempts: u32) -> bool {
+        outcome != Outcome::Success
+            && attempts < self.max_attempts.unwrap_or(3)
+            && self
+                .on
+                .as_ref()
+                .map_or(true, |outcomes| outcomes.contains(&outcome))
+    }
+
+    /// delay before the next attempt after `attempts` attempts
+    pub fn delay(&self, attempts: u32) -> Duration {
+        self.backoff
+            .as_ref()
+            .map_or(Duration::from_secs(1), |backoff| backoff.delay(attempts))
+    }
+}
+
+/// A task is flapping when its state changes max_changes times within window(s)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FlapDetection {
+    pub window: u64,
+    pub max_changes: u32,
+}
+
+/// State of a task kept across runs of a flow
+#[derive(Debug, Default, Clone)]
+pub struct TaskState {
+    /// Outcome reported by thresholds, none before the first run
+    pub reported: Option<Outcome>,
+    pub consecutive_failures: u32,
+    pub consecutive_successes: u32,
+    pub flapping: bool,
+    /// Outcome returned by the last update, flapping included
+    pub last_reported: Option<Outcome>,
+    /// Time the last reported outcome began
+    pub since: Option<SystemTime>,
+    /// Instants of state changes within the flap window
+    changes: VecDeque<Instant>,
+}
+
+impl TaskState {
+    /// record an outcome of the task, returns the outcome to report
+    pub fn update(&mut self, task: &Task, outcome: Outcome, now: Instant) -> Outcome {
+        let succeeded = outcome == Outcome::Success;
+        if succeeded {
+            self.consecutive_successes += 1;
+            self.consecutive_failures = 0;
+        } else {
+            self.consecutive_failures += 1;
+            self.consecutive_successes = 0;
+        }
+        // a task is assumed healthy before its first run, so the failure threshold
+        // applies to the first failures as well
+        let reported = match self.reported.unwrap_or(Outcome::Success) {
+            Outcome::Success if !succeeded => {
+                if self.consecutive_failures >= task.failure_threshold.unwrap_or(1) {
+                    outcome
+                } else {
+                    Outcome::Success
+                }
+            }
+            reported if reported != Outcome::Success && succeeded => {
+                if self.consecutive_successes >= task.success_threshold.unwrap_or(1) {
+                    outcome
+                } else {
+                    reported
+                }
+            }
+            _ => outcome,
+        };
+        let changed = self.reported.map_or(false, |r| {
+            (r == Outcome::Success) != (reported == Outcome::Success)
+        });
+        self.reported = Some(reported);
+
+        if let Some(flap) = &task.flap {
+            if changed {
+                self.changes.push_back(now);
+            }
+            let window = Duration::from_secs(flap.window);
+            while let Some(first) = self.changes.front() {
+                if now.duration_since(*first) > window {
+                    self.changes.pop_front();
+                } else {
+                    break;
+                }
+            }
+            self.flapping = self.changes.len() as u32 >= flap.max_changes;
+        }
+        let reported = if self.flapping {
+            Outcome::Flapping
+        } else {
+            reported
+        };
+        if self.last_reported != Some(reported) {
+            self.since = Some(SystemTime::now());
+        }
+        self.last_reported = Some(reported);
+        reported
+    }
+    /// record a run skipped because a dependency did not succeed, which counts as neither
+    /// a success nor a failure for the thresholds, returns the outcome reported before
+    pub fn skip(&mut self) -> Option<Outcome> {
+        let previous = self.last_reported;
+        if previous != Some(Outcome::Skipped) {
+            self.since = Some(SystemTime::now());
+        }
+        self.last_reported = Some(Outcome::Skipped);
+        previous
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checker::{process::ProcessChecker, Checker};
+
+    #[test]
+    fn test_retry() {
+        let retry = Retry {
+            max_attempts: Some(4),
+            backoff: Some(Backoff::Exponential {
+                initial: 1,
+                multiplier: None,
+                max: Some(3),
+            }),
+            on: Some(vec![Outcome::Timeout]),
+        };
+        assert!(retry.should_retry(Outcome::Timeout, 3));
+        assert!(!retry.should_retry(Outcome::Timeout, 4));
+        assert!(!retry.should_retry(Outcome::Failure, 1));
+        assert_eq!(retry.delay(1), Duration::from_secs(1));
+        assert_eq!(retry.delay(2), Duration::from_secs(2));
+        assert_eq!(retry.delay(3), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_task_state_thresholds() {
+        let mut task = Task::new("t", Checker::ProcessChecker(ProcessChecker::new("")));
+        task.failure_threshold = Some(2);
+        task.success_threshold = Some(2);
+        let mut state = TaskState::default();
+        let now = Instant::now();
+        // the first failures are held back by the threshold as well
+        assert_eq!(state.update(&task, Outcome::Failure, now), Outcome::Success);
+        assert_eq!(state.update(&task, Outcome::Success, now), Outcome::Success);
+        assert_eq!(state.update(&task, Outcome::Failure, now), Outcome::Success);
+        assert_eq!(state.update(&task, Outcome::Timeout, now), Outcome::Timeout);
+        assert_eq!(state.update(&task, Outcome::Success, now), Outcome::Timeout);
+        assert_eq!(state.update(&task, Outcome::Success, now), Outcome::Success);
+    }
+
+    #[test]
+    fn test_task_state_skip() {
+        let mut task = Task::new("t", Checker::ProcessChecker(ProcessChecker::new("")));
+        task.failure_threshold = Some(2);
+        let mut state = TaskState::default();
+        let now = Instant::now();
+        state.update(&task, Outcome::Failure, now);
+        assert_eq!(state.skip(), Some(Outcome::Success));
+        assert_eq!(state.skip(), Some(Outcome::Skipped));
+        // a skip does not reset the consecutive failures
+        assert_eq!(state.consecutive_failures, 1);
+        assert_eq!(state.update(&task, Outcome::Failure, now), Outcome::Failure);
+    }
+
+    #[test]
+    fn test_task_state_flapping() {
+        let mut task = Task::new("t", Checker::ProcessChecker(ProcessChecker::new("")));
+        task.flap = Some(FlapDetection {
+            window: 60,
+            max_changes: 3,
+        });
+        let mut state = TaskState::default();
+        let now = Instant::now();
+        state.update(&task, Outcome::Success, now);
+        state.update(&task, Outcome::Failure, now);
+        state.update(&task, Outcome::Success, now);
+        assert_eq!(
+            state.update(&task, Outcome::Failure, now),
+            Outcome::Flapping
+        );
+        assert!(state.flapping);
+        // the changes leave the window
+        let later = now + Duration::from_secs(61);
+        assert_eq!(
+            state.update(&task, Outcome::Failure, later),
+            Outcome::Failure
+        );
+        assert!(!state.flapping);
+    }
+}